@@ -0,0 +1,173 @@
+use std::fmt::{Display, Formatter};
+
+use crate::linker::Offset;
+use crate::value::Kind;
+
+/// Number of frames printed for a stack trace. Longer traces keep the innermost and
+/// outermost halves and summarize the frames in between.
+const MAX_TRACE_LINES: usize = 16;
+
+pub enum ErrorKind {
+    TypeMismatch {
+        expected: Kind,
+        found: Kind,
+    },
+    /// Struct field, enum payload field or closure capture past the end of `of`
+    MemberOutOfBounds {
+        of: Kind,
+        offset: Offset,
+        members: usize,
+    },
+    IndexOutOfBounds {
+        index: f64,
+        length: usize,
+    },
+    InvalidArraySize(f64),
+    EmptyArray,
+    DivisionByZero,
+    /// `CreateClosure` with a capture count that does not match the function
+    CaptureCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    CaptureOutsideClosure,
+    /// Access through a handle whose object was already collected
+    DanglingReference(Kind),
+    /// A function that was referenced but never defined
+    UnknownCallable(String),
+    /// Argument a native function cannot work with, e.g. an empty separator for split
+    InvalidArgument(String),
+    /// Pattern passed to a `Std.Regex` function that does not compile
+    InvalidPattern(String),
+    /// Function called with the wrong number of staged arguments
+    ArgumentCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    /// `CreateStruct`, `CreateArray`, `CreateEnumEntry` or `CreateClosure` reading more
+    /// values than were staged with `Argument`
+    StagedCount {
+        instruction: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// The call chain needed more than `calls` frames or `values` registers
+    StackOverflow {
+        calls: usize,
+        values: usize,
+    },
+    /// Thrown value no handler caught, converted to text
+    UncaughtThrow(String),
+}
+
+/// Error that stopped the VM, with the call chain at that point, innermost call first.
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub trace: Vec<String>,
+}
+
+impl ErrorKind {
+    /// Errors raised by native functions for bad input, which they throw as a string
+    /// instead of stopping the program.
+    pub fn is_catchable(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::InvalidArgument(_) | ErrorKind::InvalidPattern(_)
+        )
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::TypeMismatch { expected, found } => write!(
+                f,
+                "Type error: expected {}, but got {}",
+                expected.describe(),
+                found.describe()
+            ),
+            ErrorKind::MemberOutOfBounds {
+                of,
+                offset,
+                members,
+            } => write!(
+                f,
+                "Member {} is out of bounds for {} with {} members",
+                offset,
+                of.describe(),
+                members
+            ),
+            ErrorKind::IndexOutOfBounds { index, length } => write!(
+                f,
+                "Index {} is out of bounds for an array of length {}",
+                index, length
+            ),
+            ErrorKind::InvalidArraySize(size) => write!(f, "Invalid array size {}", size),
+            ErrorKind::EmptyArray => write!(f, "Cant pop from an empty array"),
+            ErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            ErrorKind::CaptureCount {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} captures {} values, but {} were given",
+                function, expected, found
+            ),
+            ErrorKind::CaptureOutsideClosure => write!(f, "LoadCapture outside of a closure"),
+            ErrorKind::DanglingReference(kind) => {
+                write!(
+                    f,
+                    "Dangling reference to a freed object, {}",
+                    kind.describe()
+                )
+            }
+            ErrorKind::UnknownCallable(name) => write!(f, "Function {} is not defined", name),
+            ErrorKind::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            ErrorKind::InvalidPattern(message) => write!(f, "Invalid pattern: {}", message),
+            ErrorKind::ArgumentCount {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} arguments, but {} were given",
+                function, expected, found
+            ),
+            ErrorKind::StagedCount {
+                instruction,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} staged values, but {} were staged",
+                instruction, expected, found
+            ),
+            ErrorKind::StackOverflow { calls, values } => write!(
+                f,
+                "Stack overflow, the limit is {} calls or {} registers",
+                calls, values
+            ),
+            ErrorKind::UncaughtThrow(value) => write!(f, "Uncaught exception: {}", value),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Runtime error: {}", self.kind)?;
+        let skipped = self.trace.len().saturating_sub(MAX_TRACE_LINES);
+        for (index, location) in self.trace.iter().enumerate() {
+            if skipped > 0 && index == MAX_TRACE_LINES / 2 {
+                writeln!(f, "    ... {} more", skipped)?;
+            }
+            if skipped == 0 || index < MAX_TRACE_LINES / 2 || index >= MAX_TRACE_LINES / 2 + skipped
+            {
+                writeln!(f, "    at {}", location)?;
+            }
+        }
+        Ok(())
+    }
+}
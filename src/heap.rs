@@ -0,0 +1,242 @@
+use std::mem::size_of;
+use std::ops::{Index, IndexMut};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+use crate::linker::Offset;
+use crate::value::{Kind, Value};
+
+/// Bytes allocated before the first collection; later thresholds grow with the live heap.
+const INITIAL_THRESHOLD: usize = 1 << 20;
+
+/// Bits of a handle's payload used for the generation, above the 32 bit slot index.
+const GENERATION_BITS: u32 = 15;
+const GENERATION_MASK: u16 = (1 << GENERATION_BITS) - 1;
+
+/// Reference to a heap slot, checked against the generation the slot had when the object
+/// was allocated. Freeing a slot bumps its generation, so handles to a freed object stop
+/// resolving instead of silently reaching whatever reuses the slot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Handle {
+    index: u32,
+    generation: u16,
+}
+
+impl Handle {
+    /// Handle packed into the 47 bit payload of a boxed [Value].
+    pub fn payload(self) -> usize {
+        (self.generation as usize) << 32 | self.index as usize
+    }
+
+    pub fn from_payload(payload: usize) -> Handle {
+        Handle {
+            index: payload as u32,
+            generation: (payload >> 32) as u16 & GENERATION_MASK,
+        }
+    }
+}
+
+/// Objects owned by the VM and referenced from boxed values by index.
+pub enum HeapObject {
+    Text(String),
+    Struct(Box<[Value]>),
+    Array(Vec<Value>),
+    /// Variant tag and payload
+    Enum(Offset, Box<[Value]>),
+    /// Callable index and captured values
+    Closure(usize, Box<[Value]>),
+    /// Shared mutable box, so closures can capture a variable instead of its value
+    Cell(Value),
+    /// Compiled pattern created by `Std.Regex.compile`, shared with the pattern cache
+    Regex(Rc<Regex>),
+    /// Slot freed by the collector, waiting on the free list
+    Free,
+}
+
+impl HeapObject {
+    /// Approximate number of bytes owned by the object, used to schedule collections.
+    fn size(&self) -> usize {
+        let payload = match self {
+            HeapObject::Text(text) => text.capacity(),
+            HeapObject::Struct(fields)
+            | HeapObject::Enum(_, fields)
+            | HeapObject::Closure(_, fields) => fields.len() * size_of::<Value>(),
+            HeapObject::Array(elements) => elements.capacity() * size_of::<Value>(),
+            HeapObject::Regex(regex) => regex.as_str().len(),
+            HeapObject::Cell(_) | HeapObject::Free => 0,
+        };
+        size_of::<HeapObject>() + payload
+    }
+
+    /// Values referenced by the object.
+    pub fn children(&self) -> &[Value] {
+        match self {
+            HeapObject::Struct(fields)
+            | HeapObject::Enum(_, fields)
+            | HeapObject::Closure(_, fields) => fields,
+            HeapObject::Array(elements) => elements,
+            HeapObject::Cell(value) => std::slice::from_ref(value),
+            HeapObject::Text(_) | HeapObject::Regex(_) | HeapObject::Free => &[],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    /// Total time spent collecting
+    pub pause: Duration,
+}
+
+/// Mark-and-sweep arena of [HeapObject]s addressed by [Handle]s. The VM decides when to
+/// collect via [Heap::should_collect] and passes every root to [Heap::collect]; freed
+/// slots are reused by later allocations under a new generation.
+pub struct Heap {
+    objects: Vec<HeapObject>,
+    generations: Vec<u16>,
+    marks: Vec<bool>,
+    free: Vec<usize>,
+    /// The first objects are the program's string pool, which `LoadString` can always
+    /// reach, so they are never freed
+    pinned: usize,
+    allocated: usize,
+    threshold: usize,
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub fn new(strings: Vec<String>) -> Heap {
+        let objects: Vec<HeapObject> = strings.into_iter().map(HeapObject::Text).collect();
+        Heap {
+            pinned: objects.len(),
+            generations: vec![0; objects.len()],
+            marks: vec![false; objects.len()],
+            objects,
+            free: vec![],
+            allocated: 0,
+            threshold: INITIAL_THRESHOLD,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    /// Collect before every allocation, which shakes out missing roots.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    #[inline(always)]
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.allocated >= self.threshold
+    }
+
+    pub fn insert(&mut self, object: HeapObject, kind: Kind) -> Value {
+        self.allocated += object.size();
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = object;
+                index
+            }
+            None => {
+                self.objects.push(object);
+                self.generations.push(0);
+                self.marks.push(false);
+                self.objects.len() - 1
+            }
+        };
+        let handle = Handle {
+            index: index as u32,
+            generation: self.generations[index],
+        };
+        Value::object(kind, handle)
+    }
+
+    /// Whether `handle` still refers to the object it was created for.
+    pub fn is_live(&self, handle: Handle) -> bool {
+        let index = handle.index as usize;
+        index < self.objects.len()
+            && self.generations[index] == handle.generation
+            && !matches!(self.objects[index], HeapObject::Free)
+    }
+
+    /// Frees every object not reachable from `roots`.
+    pub fn collect(&mut self, roots: impl Iterator<Item = Value>) {
+        let start = Instant::now();
+
+        let mut pending: Vec<usize> = vec![];
+        for value in roots {
+            self.mark(value, &mut pending);
+        }
+        while let Some(index) = pending.pop() {
+            let children = self.objects[index].children().to_vec();
+            for value in children {
+                self.mark(value, &mut pending);
+            }
+        }
+
+        let mut live = 0;
+        for index in self.pinned..self.objects.len() {
+            if self.marks[index] {
+                self.marks[index] = false;
+                live += self.objects[index].size();
+            } else if !matches!(self.objects[index], HeapObject::Free) {
+                self.stats.objects_freed += 1;
+                self.stats.bytes_freed += self.objects[index].size();
+                self.objects[index] = HeapObject::Free;
+                // A slot whose generation wraps is retired, so no old handle can ever
+                // match a later object in it
+                let generation = self.generations[index].wrapping_add(1) & GENERATION_MASK;
+                self.generations[index] = generation;
+                if generation != 0 {
+                    self.free.push(index);
+                }
+            }
+        }
+        self.marks[..self.pinned].fill(false);
+
+        self.allocated = 0;
+        self.threshold = INITIAL_THRESHOLD.max(live * 2);
+        self.stats.collections += 1;
+        self.stats.pause += start.elapsed();
+    }
+
+    /// Marks the object `value` refers to. Stale handles keep nothing alive.
+    fn mark(&mut self, value: Value, pending: &mut Vec<usize>) {
+        match value.kind() {
+            Kind::Number | Kind::Null | Kind::Function => {}
+            _ => {
+                let handle = value.handle();
+                let index = handle.index as usize;
+                if self.is_live(handle) && !self.marks[index] {
+                    self.marks[index] = true;
+                    pending.push(index);
+                }
+            }
+        }
+    }
+}
+
+impl Index<Handle> for Heap {
+    type Output = HeapObject;
+
+    fn index(&self, handle: Handle) -> &HeapObject {
+        assert!(self.is_live(handle), "Dangling handle {:?}", handle);
+        &self.objects[handle.index as usize]
+    }
+}
+
+impl IndexMut<Handle> for Heap {
+    fn index_mut(&mut self, handle: Handle) -> &mut HeapObject {
+        assert!(self.is_live(handle), "Dangling handle {:?}", handle);
+        &mut self.objects[handle.index as usize]
+    }
+}
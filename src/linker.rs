@@ -1,10 +1,11 @@
 use std::{
-    collections::{btree_map::Entry, HashMap},
+    fmt::{self, Display, Formatter},
+    mem::size_of,
     vec,
 };
 
 use crate::linker::Instruction::*;
-use crate::{ParseEntry::*, UnparsedInstruction};
+use crate::{ParseEntry::*, TryBlock, UnparsedInstruction};
 
 extern crate fxhash;
 use fxhash::FxHashMap;
@@ -13,132 +14,623 @@ use crate::{vm::Callable, ParseEntry};
 #[derive(Default)]
 pub struct Linker {
     pub instructions: Vec<Instruction>,
+    pub constants: Vec<f64>,
+    pub strings: Vec<String>,
+    pub jump_tables: Vec<JumpTable>,
+    pub handlers: Vec<Handler>,
+    pub source_map: SourceMap,
+    /// Functions referenced by `LoadFunction`, resolved once every function is fed
+    pub callables: Vec<Callable>,
+    callable_indices: FxHashMap<String, Index>,
+    constant_indices: FxHashMap<u64, Index>,
+    string_indices: FxHashMap<String, Index>,
 }
 
 impl Linker {
-    fn push(&mut self, obj: Instruction) {
+    fn push(&mut self, obj: Instruction, location: SourceLocation) {
         self.instructions.push(obj);
+        self.source_map.locations.push(location);
     }
 
-    pub fn feed_instructions(&mut self, instructions: &Vec<ParseEntry>) -> Vec<Callable> {
-        let mut labels = FxHashMap::<i32, i32>::default();
-        let additional_callables = vec![];
-        let mut function_instructions = vec![];
+    /// Returns the pool index of `value`, adding it to the pool on first use.
+    /// Numbers are compared bitwise, so `0.0` and `-0.0` stay distinct.
+    fn constant(&mut self, value: f64) -> Index {
+        let constants = &mut self.constants;
+        *self
+            .constant_indices
+            .entry(value.to_bits())
+            .or_insert_with(|| {
+                constants.push(value);
+                (constants.len() - 1) as Index
+            })
+    }
+
+    /// Returns the pool index of `value`, adding it to the pool on first use.
+    fn string(&mut self, value: String) -> Index {
+        if let Some(index) = self.string_indices.get(&value) {
+            return *index;
+        }
+        self.strings.push(value.clone());
+        let index = (self.strings.len() - 1) as Index;
+        self.string_indices.insert(value, index);
+        index
+    }
+
+    /// Returns the index of the callable named `name`, registering an unresolved
+    /// callable on first use.
+    fn callable(&mut self, name: &str) -> Index {
+        if let Some(index) = self.callable_indices.get(name) {
+            return *index;
+        }
+        self.callables.push(Callable {
+            name: name.to_string(),
+            registers: 0,
+            adress: -1,
+            args: 0,
+            capture_size: 0,
+            native: None,
+        });
+        let index = (self.callables.len() - 1) as Index;
+        self.callable_indices.insert(name.to_string(), index);
+        index
+    }
+
+    /// Links the body of `function`. Every register operand must be below `registers`,
+    /// the size of its frame, which also has to hold its `params`. The body has to end in
+    /// an instruction that never continues with the next one, so execution cannot run
+    /// past the end of the function.
+    pub fn feed_instructions(
+        &mut self,
+        file: &str,
+        function: &str,
+        registers: usize,
+        params: usize,
+        instructions: &Vec<ParseEntry>,
+    ) -> Result<(), String> {
+        if params > registers {
+            return Err(format!(
+                "{} takes {} params, but has only {} registers",
+                function, params, registers
+            ));
+        }
+        let check_register = |name: &str, register: Register| {
+            if register as usize >= registers {
+                return Err(format!(
+                    "{}: register {} is out of range, {} has {} registers",
+                    name, register, function, registers
+                ));
+            }
+            Ok(())
+        };
+        let start = self.instructions.len();
+        let function_name = function;
+        let file = self.source_map.name(file);
+        let function = self.source_map.name(function);
+        let mut label = None;
+        let mut labels = FxHashMap::<&str, Address>::default();
+        let mut fixups = vec![];
+        let mut matches = vec![];
+        let mut tries = vec![];
+        let empty = String::new();
         for entry in instructions {
-            let index = (function_instructions.len() + self.instructions.len()) as i32;
+            let index = self.instructions.len() as Address;
             match &entry {
                 ParseLabel(str) => {
-                    let label = str.parse::<f64>().unwrap() as i32;
-                    labels.insert(label, index);
+                    labels.insert(str.as_str(), index);
+                    label = Some(self.source_map.name(str));
                 }
-                ParseInstruction(UnparsedInstruction { name, params }) => {
-                    let empty = String::new();
-
-                    let s1 = params.get(0).unwrap_or(&empty);
+                ParseTry(block) => tries.push(block),
+                ParseInstruction(UnparsedInstruction { name, params, line }) => {
+                    let s1 = params.first().unwrap_or(&empty);
                     let s2 = params.get(1).unwrap_or(&empty);
-                    let s3 = params.get(2).unwrap_or(&empty);
 
-                    let i0 = s1.parse::<f64>().unwrap_or(0.0) as i8;
-                    let i1 = s2.parse::<f64>().unwrap_or(0.0) as i8;
-                    let i2 = s3.parse::<f64>().unwrap_or(0.0) as i8;
+                    let r0 = parse_register(name, params, 0);
+                    let r1 = parse_register(name, params, 1);
+                    let r2 = parse_register(name, params, 2);
 
                     let f0 = s1.parse::<f64>().unwrap_or(0.0);
                     let f1 = s2.parse::<f64>().unwrap_or(0.0);
-                    let f2 = s3.parse::<f64>().unwrap_or(0.0);
-
-                    print!("Instruction {} ", name);
-                    print!(" {} ", i0);
-                    print!(" {} ", i1);
-                    println!(" {} ", i2);
-
-                    function_instructions.push(match name.as_str() {
-                        "Add" => Add(i0, i1, i2),
-                        "Smaller" => Smaller(i0, i1, i2),
-                        "LoadConst" => LoadConst(i0, f1),
-                        "Exit" => Exit(f0),
-                        "Debug" => Debug(i0),
-                        "Argument" => Argument(i0, i1),
-                        "CreateStruct" => CreateStruct(i0, i1),
-                        "Jump" => Jump(Box::new(Label {
-                            index: i0,
-                            adress: -1,
-                        })),
-                        "JumpIfNot" => JumpIfNot(
-                            i0,
-                            Box::new(Label {
-                                index: i1,
-                                adress: -1,
-                            }),
-                        ),
+
+                    let instruction = match name.as_str() {
+                        "Nop" => Nop,
+                        "Add" => Add(r0?, r1?, r2?),
+                        "Subtract" => Subtract(r0?, r1?, r2?),
+                        "Multiply" => Multiply(r0?, r1?, r2?),
+                        "Divide" => Divide(r0?, r1?, r2?),
+                        "Negate" => Negate(r0?, r1?),
+                        "Greater" => Greater(r0?, r1?, r2?),
+                        "GreaterEq" => GreaterEq(r0?, r1?, r2?),
+                        "Smaller" => Smaller(r0?, r1?, r2?),
+                        "SmallerEq" => SmallerEq(r0?, r1?, r2?),
+                        "Equals" => Equals(r0?, r1?, r2?),
+                        "NonEquals" => NonEquals(r0?, r1?, r2?),
+                        "Concat" => Concat(r0?, r1?, r2?),
+                        "StringEquals" => StringEquals(r0?, r1?, r2?),
+                        "StringNonEquals" => StringNonEquals(r0?, r1?, r2?),
+                        "Not" => Not(r0?, r1?),
+                        "Or" => Or(r0?, r1?, r2?),
+                        "And" => And(r0?, r1?, r2?),
+                        "LoadConst" => LoadConst(r0?, self.constant(f1)),
+                        "LoadNull" => LoadNull(r0?),
+                        "LoadString" => LoadString(r0?, self.string(unescape(s2))),
+                        "Exit" => Exit(self.constant(f0)),
+                        "Debug" => Debug(r0?),
+                        "Argument" => Argument(r0?, r1?),
+                        "CreateStruct" => CreateStruct(r0?, r1?),
+                        "CreateArray" => CreateArray(r0?, r1?),
+                        "CreateArraySized" => CreateArraySized(r0?, r1?),
+                        "LoadArray" => LoadArray(r0?, r1?, r2?),
+                        "StoreArray" => StoreArray(r0?, r1?, r2?),
+                        "ArrayLength" => ArrayLength(r0?, r1?),
+                        "ArrayPush" => ArrayPush(r0?, r1?),
+                        "ArrayPop" => ArrayPop(r0?, r1?),
+                        "CreateEnumEntry" => CreateEnumEntry(r0?, r1?, r2?),
+                        "LoadEnumType" => LoadEnumType(r0?, r1?),
+                        "LoadEnumMember" => LoadEnumMember(r0?, r1?, r2?),
+                        "CopyEnumMember" => CopyEnumMember(r0?, r1?, r2?),
+                        "CreateClosure" => CreateClosure(r0?, r1?),
+                        "LoadCapture" => LoadCapture(r0?, r1?),
+                        "CreateCell" => CreateCell(r0?, r1?),
+                        "LoadCell" => LoadCell(r0?, r1?),
+                        "StoreCell" => StoreCell(r0?, r1?),
+                        "LoadMember" => LoadMember(r0?, r1?, r2?),
+                        "StoreMember" => StoreMember(r0?, r1?, r2?),
+                        "Copy" => Copy(r0?, r1?),
+                        "LoadFunction" => LoadFunction(r0?, self.callable(s2)),
+                        "InvokeFunction" => InvokeFunction(r0?, r1?),
+                        "Return" => Return(r0?),
+                        "Throw" => Throw(r0?),
+                        "Jump" => {
+                            fixups.push((index, label_name(s1)));
+                            Jump(0)
+                        }
+                        "Match" => {
+                            let arms = parse_arms(params.get(2).unwrap_or(&empty))?;
+                            matches.push((index, label_name(s2), arms));
+                            Match(r0?, 0)
+                        }
+                        "JumpIfNot" => {
+                            fixups.push((index, label_name(s2)));
+                            JumpIfNot(r0?, 0)
+                        }
+                        "JumpIf" => {
+                            fixups.push((index, label_name(s2)));
+                            JumpIf(r0?, 0)
+                        }
                         _ => {
-                            panic!("Unknown cmd {}", name)
+                            return Err(format!("Unknown cmd {}", name));
                         }
-                    });
+                    };
+                    for register in instruction.registers() {
+                        check_register(name, register)?;
+                    }
+                    let location = SourceLocation {
+                        file,
+                        function,
+                        label,
+                        line: *line as u32,
+                    };
+                    self.push(instruction, location);
                 }
             }
         }
-        for ele in function_instructions {
-            self.push(match ele {
-                Jump(target) => {
-                    if let Some(adress) = labels.get(&(target.index as i32)) {
-                        Jump(Box::new(Label {
-                            index: target.index,
-                            adress: *adress,
-                        }))
-                    } else {
-                        panic!("Jump: Cant find Adress of Label {}", target.index);
-                    }
+        let end = self.instructions.len() as Address;
+        let falls_through = match self.instructions[start..].last() {
+            Some(Return(_) | Exit(_) | Jump(_) | Match(..) | Throw(_)) => {
+                labels.values().any(|adress| *adress == end)
+            }
+            _ => true,
+        };
+        if falls_through {
+            return Err(format!(
+                "{} can run past its end; end it with Return, Exit, Jump, Match or Throw",
+                function_name
+            ));
+        }
+        let resolve = |label: &str| match labels.get(label) {
+            Some(adress) => Ok(*adress),
+            None => Err(format!("Cant find Adress of Label {}", label)),
+        };
+        for (position, label) in fixups {
+            let target = resolve(label)?;
+            match &mut self.instructions[position as usize] {
+                Jump(adress) | JumpIfNot(_, adress) | JumpIf(_, adress) => *adress = target,
+                _ => unreachable!("Only jumps reference labels"),
+            }
+        }
+        for (position, default, arms) in matches {
+            let default = resolve(default)?;
+            let mut targets = Vec::with_capacity(arms.len());
+            for (tag, label) in arms {
+                targets.push((tag, resolve(label)?));
+            }
+            self.jump_tables.push(JumpTable::new(default, targets)?);
+            match &mut self.instructions[position as usize] {
+                Match(_, table) => *table = (self.jump_tables.len() - 1) as Index,
+                _ => unreachable!("Only matches reference jump tables"),
+            }
+        }
+
+        for TryBlock {
+            start,
+            end,
+            handler,
+            register,
+        } in tries
+        {
+            check_register("try", *register)?;
+            let start_adress = resolve(label_name(start))?;
+            let end_adress = resolve(label_name(end))?;
+            if start_adress > end_adress {
+                return Err(format!("try: {} comes after {}", start, end));
+            }
+            self.handlers.push(Handler {
+                start: start_adress,
+                end: end_adress,
+                target: resolve(label_name(handler))?,
+                register: *register,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Finishes linking and moves the instructions and their side tables into a [Program].
+    pub fn link(self) -> Program {
+        Program {
+            instructions: self.instructions.into_boxed_slice(),
+            constants: self.constants.into_boxed_slice(),
+            strings: self.strings.into_boxed_slice(),
+            callables: self.callables.into_boxed_slice(),
+            jump_tables: self.jump_tables.into_boxed_slice(),
+            handlers: self.handlers.into_boxed_slice(),
+            source_map: self.source_map,
+        }
+    }
+}
+
+/// Labels are declared as `#name`; jumps may reference them with or without the `#`.
+fn label_name(str: &str) -> &str {
+    str.strip_prefix('#').unwrap_or(str)
+}
+
+/// Parses the arm list of a `Match`, e.g. `[0 -> #some, 1 -> #none]`.
+fn parse_arms(str: &str) -> Result<Vec<(Offset, &str)>, String> {
+    let inner = str
+        .strip_prefix('[')
+        .and_then(|str| str.strip_suffix(']'))
+        .ok_or_else(|| format!("Match: expected a list of arms, but got \"{}\"", str))?;
+    let mut arms = vec![];
+    for arm in inner.split(',').filter(|arm| !arm.is_empty()) {
+        let (tag, label) = arm
+            .split_once("->")
+            .ok_or_else(|| format!("Match: expected \"tag -> #label\", but got \"{}\"", arm))?;
+        let tag = tag
+            .trim()
+            .parse::<Offset>()
+            .map_err(|_| format!("Match: invalid variant tag \"{}\"", tag))?;
+        arms.push((tag, label_name(label.trim())));
+    }
+    Ok(arms)
+}
+
+/// Resolves the escape sequences `transform_arguments` keeps inside string literals.
+fn unescape(str: &str) -> String {
+    let mut result = String::with_capacity(str.len());
+    let mut iter = str.chars();
+    while let Some(char) = iter.next() {
+        if char != '\\' {
+            result.push(char);
+            continue;
+        }
+        match iter.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('0') => result.push('\0'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Parses the register or offset operand at `index`. Missing operands default to 0,
+/// anything that does not fit into a [Register] is rejected instead of truncated.
+fn parse_register(name: &str, params: &[String], index: usize) -> Result<Register, String> {
+    let param = match params.get(index) {
+        Some(param) => param,
+        None => return Ok(0),
+    };
+    match param.parse::<u64>() {
+        Ok(value) if value <= MAX_REGISTER as u64 => Ok(value as Register),
+        Ok(value) => Err(format!(
+            "{}: operand {} is {}, but at most {} is supported",
+            name,
+            index + 1,
+            value,
+            MAX_REGISTER
+        )),
+        Err(_) => Err(format!(
+            "{}: operand {} \"{}\" is not a valid register or offset",
+            name,
+            index + 1,
+            param
+        )),
+    }
+}
+
+/// Registers and offsets are encoded as 16 bit values, so functions can use up to
+/// 65536 registers and structs or enums can have up to 65536 fields or variants.
+pub type Register = u16;
+pub type Offset = u16;
+pub const MAX_REGISTER: Register = Register::MAX;
+
+/// Index into one of the side tables of a [Program].
+pub type Index = u32;
+/// Absolute position in [Program::instructions].
+pub type Address = u32;
+
+/// A linked program. Instructions are fixed-width words; everything that does not fit
+/// into a word lives in a side table and is referenced by [Index].
+pub struct Program {
+    pub instructions: Box<[Instruction]>,
+    pub constants: Box<[f64]>,
+    pub strings: Box<[String]>,
+    pub callables: Box<[Callable]>,
+    pub jump_tables: Box<[JumpTable]>,
+    pub handlers: Box<[Handler]>,
+    pub source_map: SourceMap,
+}
+
+/// Exception handler covering the adresses `start..end` of a single function.
+#[derive(Clone, Copy)]
+pub struct Handler {
+    pub start: Address,
+    pub end: Address,
+    pub target: Address,
+    /// Register of the function's frame receiving the thrown value
+    pub register: Register,
+}
+
+/// Position in the assembly source an instruction was emitted from. Names are
+/// interned in [SourceMap::names].
+#[derive(Clone, Copy)]
+pub struct SourceLocation {
+    pub file: Index,
+    pub function: Index,
+    /// The closest label preceding the instruction inside its function
+    pub label: Option<Index>,
+    pub line: u32,
+}
+
+/// Maps every instruction of a [Program] back to its [SourceLocation].
+#[derive(Default)]
+pub struct SourceMap {
+    pub names: Vec<String>,
+    /// One entry per instruction, indexed by adress
+    pub locations: Vec<SourceLocation>,
+    name_indices: FxHashMap<String, Index>,
+}
+
+impl SourceMap {
+    fn name(&mut self, name: &str) -> Index {
+        if let Some(index) = self.name_indices.get(name) {
+            return *index;
+        }
+        self.names.push(name.to_string());
+        let index = (self.names.len() - 1) as Index;
+        self.name_indices.insert(name.to_string(), index);
+        index
+    }
+
+    pub fn function(&self, adress: usize) -> Option<&str> {
+        let location = self.locations.get(adress)?;
+        Some(&self.names[location.function as usize])
+    }
+
+    /// Describes the source position of the instruction at `adress`, e.g.
+    /// `res/input.txt:12 in Main.main at #loop`.
+    pub fn describe(&self, adress: usize) -> String {
+        let location = match self.locations.get(adress) {
+            Some(location) => location,
+            None => return format!("<unknown adress {}>", adress),
+        };
+        let mut description = format!(
+            "{}:{} in {}",
+            self.names[location.file as usize],
+            location.line,
+            self.names[location.function as usize]
+        );
+        if let Some(label) = location.label {
+            description += &format!(" at #{}", self.names[label as usize]);
+        }
+        description
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "constants:")?;
+        for (index, constant) in self.constants.iter().enumerate() {
+            writeln!(f, "  #{} = {}", index, constant)?;
+        }
+        writeln!(f, "strings:")?;
+        for (index, string) in self.strings.iter().enumerate() {
+            writeln!(f, "  ${} = {:?}", index, string)?;
+        }
+        writeln!(f, "jump tables:")?;
+        for (index, table) in self.jump_tables.iter().enumerate() {
+            writeln!(f, "  %{} = {}", index, table)?;
+        }
+        writeln!(f, "handlers:")?;
+        for handler in self.handlers.iter() {
+            writeln!(
+                f,
+                "  {:04}..{:04} -> {:04}, {}",
+                handler.start, handler.end, handler.target, handler.register
+            )?;
+        }
+        writeln!(f, "code:")?;
+        let mut function = None;
+        for (adress, instruction) in self.instructions.iter().enumerate() {
+            let location = self.source_map.locations.get(adress);
+            if location.map(|location| location.function) != function {
+                function = location.map(|location| location.function);
+                writeln!(f, "{}:", self.source_map.function(adress).unwrap_or("?"))?;
+            }
+            write!(f, "  {:04} {:?}", adress, instruction)?;
+            match instruction {
+                LoadConst(_, index) | Exit(index) => {
+                    write!(f, " ; #{} = {}", index, self.constants[*index as usize])?
                 }
-                JumpIfNot(register, target) => {
-                    if let Some(adress) = labels.get(&(target.index as i32)) {
-                        JumpIfNot(
-                            register,
-                            Box::new(Label {
-                                index: target.index,
-                                adress: *adress,
-                            }),
-                        )
-                    } else {
-                        panic!("JumpIfNot: Cant find Adress of Label {}", target.index);
-                    }
+                LoadString(_, index) => {
+                    write!(f, " ; ${} = {:?}", index, self.strings[*index as usize])?
                 }
-                rest => rest,
-            });
+                Match(_, index) => write!(f, " ; %{}", index)?,
+                LoadFunction(_, index) => write!(f, " ; {}", self.callables[*index as usize].name)?,
+                _ => {}
+            }
+            if let Some(location) = location {
+                write!(f, " ; line {}", location.line)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Dispatch table of a `Match`. Contiguous variant tags are looked up by position,
+/// everything else by binary search over the sorted arms.
+pub enum JumpTable {
+    Dense {
+        first: Offset,
+        targets: Box<[Address]>,
+        default: Address,
+    },
+    Sparse {
+        arms: Box<[(Offset, Address)]>,
+        default: Address,
+    },
+}
+
+impl JumpTable {
+    fn new(default: Address, mut arms: Vec<(Offset, Address)>) -> Result<JumpTable, String> {
+        arms.sort_by_key(|(tag, _)| *tag);
+        if let Some(pair) = arms.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("Match: duplicate arm for variant {}", pair[0].0));
+        }
+        let first = arms.first().map_or(0, |(tag, _)| *tag);
+        let contiguous = arms
+            .iter()
+            .enumerate()
+            .all(|(index, (tag, _))| *tag as usize == first as usize + index);
+        Ok(if contiguous {
+            JumpTable::Dense {
+                first,
+                targets: arms.into_iter().map(|(_, adress)| adress).collect(),
+                default,
+            }
+        } else {
+            JumpTable::Sparse {
+                arms: arms.into_boxed_slice(),
+                default,
+            }
+        })
+    }
+
+    #[inline]
+    pub fn target(&self, tag: usize) -> Address {
+        match self {
+            JumpTable::Dense {
+                first,
+                targets,
+                default,
+            } => tag
+                .checked_sub(*first as usize)
+                .and_then(|index| targets.get(index))
+                .copied()
+                .unwrap_or(*default),
+            JumpTable::Sparse { arms, default } => arms
+                .binary_search_by_key(&tag, |(tag, _)| *tag as usize)
+                .map_or(*default, |index| arms[index].1),
         }
+    }
+}
 
-        additional_callables
+impl Display for JumpTable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let default = match self {
+            JumpTable::Dense {
+                first,
+                targets,
+                default,
+            } => {
+                write!(f, "dense [")?;
+                for (index, target) in targets.iter().enumerate() {
+                    let separator = if index == 0 { "" } else { ", " };
+                    write!(
+                        f,
+                        "{}{} -> {:04}",
+                        separator,
+                        *first as usize + index,
+                        target
+                    )?;
+                }
+                default
+            }
+            JumpTable::Sparse { arms, default } => {
+                write!(f, "sparse [")?;
+                for (index, (tag, target)) in arms.iter().enumerate() {
+                    let separator = if index == 0 { "" } else { ", " };
+                    write!(f, "{}{} -> {:04}", separator, tag, target)?;
+                }
+                default
+            }
+        };
+        write!(f, "] else {:04}", default)
     }
 }
 
-type Register = i8;
-type Offset = i8;
+#[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     Nop,
     Debug(Register),
-    LoadConst(Register, f64),
+    LoadConst(Register, Index),
+    LoadNull(Register),
     Copy(Register, Register),
     Not(Register, Register),
     Negate(Register, Register),
-    LoadString(Register, Box<String>),
-    LoadFunction(Register, Box<Callable>),
+    LoadString(Register, Index),
+    LoadFunction(Register, Index),
     Argument(Offset, Register),
-    Exit(f64),
+    Exit(Index),
     InvokeFunction(Register, Register),
     Return(Register),
-    JumpIfNot(Register, Box<Label>),
-    Jump(Box<Label>),
+    JumpIfNot(Register, Address),
+    JumpIf(Register, Address),
+    Jump(Address),
     LoadMember(Register, Register, Offset),
     LoadArray(Register, Register, Register),
     StoreMember(Register, Register, Offset),
     StoreArray(Register, Register, Register),
     CreateStruct(Register, Offset),
+    CreateArray(Register, Offset),
+    CreateArraySized(Register, Register),
+    ArrayLength(Register, Register),
+    ArrayPush(Register, Register),
+    ArrayPop(Register, Register),
     CreateEnumEntry(Register, Offset, Offset),
     CreateClosure(Register, Offset),
+    LoadCapture(Register, Offset),
+    CreateCell(Register, Register),
+    LoadCell(Register, Register),
+    StoreCell(Register, Register),
     LoadEnumType(Register, Register),
     LoadEnumMember(Register, Register, Offset),
     CopyEnumMember(Register, Register, Offset),
     Throw(Register),
-    Match(Register, Box<Label>, Box<FxHashMap<Register, Label>>),
+    Match(Register, Index),
     Add(Register, Register, Register),
     Subtract(Register, Register, Register),
     Multiply(Register, Register, Register),
@@ -156,7 +648,64 @@ pub enum Instruction {
     Concat(Register, Register, Register),
 }
 
-pub struct Label {
-    pub index: i8,
-    pub adress: i32,
+// Keep instructions a single 8 byte word, so dispatch never has to chase a pointer.
+const _: () = assert!(size_of::<Instruction>() == 8);
+
+impl Instruction {
+    /// Operands that name a register of the running frame, as opposed to offsets,
+    /// counts and table indices.
+    fn registers(&self) -> Vec<Register> {
+        match *self {
+            Nop | Exit(_) | Jump(_) => vec![],
+            Debug(a)
+            | LoadConst(a, _)
+            | LoadNull(a)
+            | LoadString(a, _)
+            | LoadFunction(a, _)
+            | Argument(_, a)
+            | Return(a)
+            | JumpIfNot(a, _)
+            | JumpIf(a, _)
+            | CreateStruct(a, _)
+            | CreateArray(a, _)
+            | CreateEnumEntry(a, _, _)
+            | CreateClosure(a, _)
+            | LoadCapture(a, _)
+            | Throw(a)
+            | Match(a, _) => vec![a],
+            Copy(a, b)
+            | Not(a, b)
+            | Negate(a, b)
+            | InvokeFunction(a, b)
+            | LoadMember(a, b, _)
+            | StoreMember(a, b, _)
+            | CreateArraySized(a, b)
+            | ArrayLength(a, b)
+            | ArrayPush(a, b)
+            | ArrayPop(a, b)
+            | CreateCell(a, b)
+            | LoadCell(a, b)
+            | StoreCell(a, b)
+            | LoadEnumType(a, b)
+            | LoadEnumMember(a, b, _)
+            | CopyEnumMember(a, b, _) => vec![a, b],
+            LoadArray(a, b, c)
+            | StoreArray(a, b, c)
+            | Add(a, b, c)
+            | Subtract(a, b, c)
+            | Multiply(a, b, c)
+            | Divide(a, b, c)
+            | Or(a, b, c)
+            | And(a, b, c)
+            | Greater(a, b, c)
+            | GreaterEq(a, b, c)
+            | Smaller(a, b, c)
+            | SmallerEq(a, b, c)
+            | Equals(a, b, c)
+            | NonEquals(a, b, c)
+            | StringNonEquals(a, b, c)
+            | StringEquals(a, b, c)
+            | Concat(a, b, c) => vec![a, b, c],
+        }
+    }
 }
@@ -1,14 +1,21 @@
+mod error;
+mod heap;
 mod linker;
+mod native;
+mod stdlib;
+mod value;
 mod vm;
 
 use num_format::{Locale, ToFormattedString};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::{fs::File, io::Read, vec};
 
 use regex::Regex;
 
-use crate::linker::Linker;
-use crate::vm::{Callable, VM};
+use crate::linker::{Linker, Register, MAX_REGISTER};
+use crate::native::{register_system, Natives};
+use crate::stdlib::register_std;
+use crate::vm::{RunOutcome, VM};
 use crate::Node::*;
 use crate::ParseEntry::*;
 
@@ -29,33 +36,62 @@ fn main() {
     let lst = Node::construct(&[1, 2, 3, 4, 5, 6]);
     lst.print();
 
-    let mut file = File::open("res/input.txt").unwrap();
+    let disassemble = std::env::args().any(|arg| arg == "--disassemble");
+    let gc_stress = std::env::args().any(|arg| arg == "--gc-stress");
+    let gc_stats = std::env::args().any(|arg| arg == "--gc-stats");
+    let path = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .unwrap_or_else(|| "res/input.txt".to_string());
+    let mut file = File::open(&path).unwrap();
     let mut result = String::new();
     file.read_to_string(&mut result)
         .expect("Cant read input File");
     let cfg = ParserConfig::new();
-    let mut lines = result.lines();
-    let mut time = SystemTime::now();
+    let mut lines = result.lines().enumerate();
 
     let mut list = generate(&mut lines, &cfg).expect("Error generating");
-    let mut callables: Vec<Callable> = vec![];
+    let mut natives = Natives::default();
+    register_system(&mut natives);
+    register_std(&mut natives);
     let mut linker: Linker = Default::default();
-    for function in &mut list {
+    // Native functions have no body to link
+    for function in list.iter_mut().filter(|function| !function.external) {
         let adress = linker.instructions.len();
         function.temp_adress = adress as i32;
-        let mut referenced = linker.feed_instructions(&function.instructions);
-        callables.append(&mut referenced);
+        linker
+            .feed_instructions(
+                &path,
+                &function.name,
+                function.size as usize,
+                function.args as usize,
+                &function.instructions,
+            )
+            .expect("Error linking");
     }
 
-    for callable in &mut callables {
+    // Functions that are never defined keep adress -1 and fail once they are called
+    for callable in &mut linker.callables {
         let name = &callable.name;
-        let query = list.iter().find(|ele| ele.name.eq(name.as_ref()));
-        if let Some(fcn) = query {
+        let query = list.iter().find(|ele| ele.name.eq(name));
+        if let Some(fcn) = query.filter(|fcn| fcn.external) {
+            let native = natives
+                .index(name)
+                .unwrap_or_else(|| panic!("No native function {}", name));
+            let expected = natives.get(native).args;
+            if fcn.args as usize != expected {
+                panic!(
+                    "{} is declared with {} arguments, but takes {}",
+                    name, fcn.args, expected
+                );
+            }
+            callable.args = fcn.args;
+            callable.native = Some(native);
+        } else if let Some(fcn) = query {
             callable.adress = fcn.temp_adress;
             callable.args = fcn.args;
             callable.registers = fcn.size;
-        } else {
-            panic!("Cant find function {}", name);
+            callable.capture_size = fcn.captures;
         }
     }
     let query = list
@@ -63,12 +99,67 @@ fn main() {
         .find(|ele| ele.name.eq("Main.main"))
         .expect("Find Main Function");
     println!("{}", linker.instructions.len());
-    let mut vm = VM::new(linker.instructions);
-    vm.start(query.temp_adress as usize, query.args as usize);
-    time = SystemTime::now();
-    while vm.running() {
-        vm.tick();
+    let program = linker.link();
+    if disassemble {
+        print!("{}", program);
+        return;
+    }
+    let mut vm = VM::new(program, natives);
+    vm.set_gc_stress(gc_stress);
+    if let Some(limits) = flag_value("--stack-limits") {
+        let limits: Vec<usize> = limits
+            .split(',')
+            .map(|limit| limit.parse().expect("Invalid stack limit"))
+            .collect();
+        match limits[..] {
+            [calls, values] => vm.set_stack_limits(calls, values),
+            _ => panic!("Expected --stack-limits=CALLS,REGISTERS"),
+        }
     }
+    if let Err(error) = vm.start(query.temp_adress as usize, query.size as usize) {
+        print!("{}", error);
+        std::process::exit(1);
+    }
+    let time = SystemTime::now();
+    if let Some(timeout) = flag_value("--timeout") {
+        let timeout = Duration::from_millis(timeout.parse().expect("Invalid timeout"));
+        let interrupt = vm.interrupt_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            interrupt.interrupt();
+        });
+    }
+    let budget = flag_value("--budget").map_or(u64::MAX, |budget| {
+        budget.parse().expect("Invalid step budget")
+    });
+    // Runs in slices of `--slice=N` steps, resuming after each, like a host that
+    // time-slices programs
+    let slice = flag_value("--slice").map_or(u64::MAX, |slice| {
+        slice.parse().expect("Invalid slice length")
+    });
+    let mut remaining = budget;
+    let outcome = loop {
+        let steps = slice.min(remaining);
+        match vm.run(steps) {
+            RunOutcome::OutOfBudget if remaining > steps => remaining -= steps,
+            outcome => break outcome,
+        }
+    };
+    let exit_code = match outcome {
+        RunOutcome::Exited(exit_code) => exit_code,
+        RunOutcome::OutOfBudget => {
+            println!("Stopped after {} steps", budget);
+            vm.exit_code()
+        }
+        RunOutcome::Interrupted => {
+            println!("Interrupted at {}", vm.stack_trace()[0]);
+            vm.exit_code()
+        }
+        RunOutcome::Failed(error) => {
+            print!("{}", error);
+            std::process::exit(1);
+        }
+    };
     let later = SystemTime::now();
     let length = later.duration_since(time).unwrap().as_millis();
     let length_sec = (length as f64) / 1000.0;
@@ -77,60 +168,118 @@ fn main() {
         "Took {}ms with {} steps ({} Instructions per Second)",
         length, vm.dbg_iter, format
     );
+    if gc_stats {
+        let stats = vm.gc_stats();
+        println!(
+            "GC: {} collections freed {} objects ({} bytes) in {}ms",
+            stats.collections,
+            stats.objects_freed,
+            stats.bytes_freed,
+            stats.pause.as_millis()
+        );
+    }
+    std::process::exit(exit_code as i32);
+}
+
+/// Value of a `--name=value` command line flag.
+fn flag_value(name: &str) -> Option<String> {
+    std::env::args().find_map(|arg| {
+        arg.strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+            .map(str::to_string)
+    })
 }
 
 struct ParserConfig {
+    extern_regex: Regex,
     function_regex: Regex,
     label_regex: Regex,
+    try_regex: Regex,
     register_regex: Regex,
     params_regex: Regex,
-    define_regex: Regex,
+    captures_regex: Regex,
     instruction_regex: Regex,
 }
 
 impl ParserConfig {
     fn new() -> ParserConfig {
         ParserConfig {
+            extern_regex: Regex::new(r"^\s*extern\s+fn\s+([\w.#]+)\s+(\d+)\s*$").unwrap(),
             function_regex: Regex::new(r"fn\s+([\w.#]+)").unwrap(),
             label_regex: Regex::new(r"#([\w.]+)").unwrap(),
+            try_regex: Regex::new(
+                r"^\s*try\s+#([\w.]+)\s+#([\w.]+)\s*->\s*#([\w.]+)(?:\s*,\s*(\S+))?\s*$",
+            )
+            .unwrap(),
             register_regex: Regex::new(r"registers\s+(.+)?").unwrap(),
             params_regex: Regex::new(r"params\s+(.+)").unwrap(),
-            define_regex: Regex::new(r"define\s+(\w+)\s+(.*)").unwrap(),
+            captures_regex: Regex::new(r"captures\s+(.+)").unwrap(),
             instruction_regex: Regex::new(r"\s*(\w+)\s*:\s*(.*)").unwrap(),
         }
     }
 }
 
 fn generate(
-    lines: &mut dyn Iterator<Item = &str>,
+    lines: &mut dyn Iterator<Item = (usize, &str)>,
     config: &ParserConfig,
 ) -> Result<Vec<Function>, String> {
     let mut list = vec![];
 
-    while let Some(arg) = lines.next() {
-        if let Some(captures) = config.function_regex.captures(arg) {
+    while let Some((_, arg)) = lines.next() {
+        if let Some(captures) = config.extern_regex.captures(arg) {
+            // `extern fn Sys.print 1` declares a native function taking one argument
+            list.push(Function {
+                name: captures.get(1).unwrap().as_str().to_string(),
+                size: 0,
+                instructions: vec![],
+                args: captures.get(2).unwrap().as_str().parse().unwrap(),
+                captures: 0,
+                temp_adress: 0,
+                external: true,
+            });
+        } else if let Some(captures) = config.function_regex.captures(arg) {
             let name = captures.get(1).unwrap().as_str();
             let mut instructions = vec![];
             let mut register_target = None;
-            while let Some(arg) = lines.next() {
+            for (line, arg) in &mut *lines {
                 if let Some(arg) = config.instruction_regex.captures(arg) {
                     let name = arg.get(1).unwrap().as_str();
                     let args = arg.get(2).unwrap().as_str();
                     let name = name.to_string();
                     let args = args.to_string();
                     let params = transform_arguments(args);
-                    instructions.push(ParseInstruction(UnparsedInstruction { name, params }));
+                    instructions.push(ParseInstruction(UnparsedInstruction {
+                        name,
+                        params,
+                        line: line + 1,
+                    }));
+                } else if let Some(arg) = config.try_regex.captures(arg) {
+                    let label = |index: usize| arg.get(index).unwrap().as_str().to_string();
+                    let register = match arg.get(4) {
+                        Some(register) => match register.as_str().parse::<Register>() {
+                            Ok(register) => register,
+                            Err(_) => {
+                                return Err(format!(
+                                    "Invalid exception register {}",
+                                    register.as_str()
+                                ))
+                            }
+                        },
+                        None => 0,
+                    };
+                    instructions.push(ParseTry(TryBlock {
+                        start: label(1),
+                        end: label(2),
+                        handler: label(3),
+                        register,
+                    }));
                 } else if let Some(arg) = config.label_regex.captures(arg) {
                     let jump_label = arg.get(1).unwrap().as_str();
                     instructions.push(ParseLabel(jump_label.trim().to_string()));
                 } else {
                     if let Some(params) = config.register_regex.captures(arg) {
-                        let parse = params.get(1).unwrap().as_str().to_string().parse::<f64>();
-                        if let Ok(double) = parse {
-                            register_target = Some(double as i32);
-                        } else {
-                            return Err("Cant parse register argument as number".to_string());
-                        }
+                        let count = params.get(1).map_or("", |count| count.as_str());
+                        register_target = Some(parse_count(name, "registers", count)?);
                     } else {
                         return Err(format!("Expected \"registers\" parameter, but got {}", arg));
                     }
@@ -138,15 +287,10 @@ fn generate(
                 }
             }
 
-            let params = match lines.next() {
+            let params = match lines.next().map(|(_, line)| line) {
                 Some(params) => {
                     if let Some(params) = config.params_regex.captures(params) {
-                        let parse = params.get(1).unwrap().as_str().to_string().parse::<f64>();
-                        if let Ok(double) = parse {
-                            double as i32
-                        } else {
-                            return Err("Cant parse params argument as number".to_string());
-                        }
+                        parse_count(name, "params", params.get(1).unwrap().as_str())?
                     } else {
                         return Err("Expected \"params\" parameter".to_string());
                     }
@@ -156,7 +300,16 @@ fn generate(
                 }
             };
 
-            match lines.next() {
+            let mut captures = 0;
+            let mut next = lines.next().map(|(_, line)| line);
+            if let Some(line) = next {
+                if let Some(params) = config.captures_regex.captures(line) {
+                    captures = parse_count(name, "captures", params.get(1).unwrap().as_str())?;
+                    next = lines.next().map(|(_, line)| line);
+                }
+            }
+
+            match next {
                 Some(params) => {
                     if params != "end" {
                         return Err("Expected \"end\" parameter".to_string());
@@ -170,40 +323,81 @@ fn generate(
             list.push(Function {
                 name: name.to_string(),
                 args: params,
+                captures,
                 instructions,
                 size: register_target.unwrap(),
                 temp_adress: 0,
+                external: false,
             });
         }
     }
     Ok(list)
 }
 
+/// Parses the count of a `registers`, `params` or `captures` line. Counts are whole
+/// numbers up to the number of registers a frame can have.
+fn parse_count(function: &str, what: &str, count: &str) -> Result<i32, String> {
+    let max = MAX_REGISTER as usize + 1;
+    match count.trim().parse::<usize>() {
+        Ok(value) if value <= max => Ok(value as i32),
+        Ok(_) => Err(format!(
+            "Function {}: \"{} {}\" is more than the {} supported",
+            function, what, count, max
+        )),
+        Err(_) => Err(format!(
+            "Function {}: \"{} {}\" is not a whole number",
+            function, what, count
+        )),
+    }
+}
+
 struct Function {
     name: String,
     size: i32,
     instructions: Vec<ParseEntry>,
     args: i32,
+    captures: i32,
     temp_adress: i32,
+    /// Declared with `extern fn`, implemented by a native function
+    external: bool,
 }
 
 pub enum ParseEntry {
     ParseInstruction(UnparsedInstruction),
     ParseLabel(String),
+    ParseTry(TryBlock),
+}
+
+/// `try #start #end -> #handler, register`: exceptions thrown between `#start` and
+/// `#end` continue at `#handler` with the thrown value in `register` (default 0).
+pub struct TryBlock {
+    start: String,
+    end: String,
+    handler: String,
+    register: Register,
 }
 
 pub struct UnparsedInstruction {
     name: String,
     params: Vec<String>,
+    line: usize,
 }
 
 fn transform_arguments(str: String) -> Vec<String> {
     let mut arguments = vec![];
     let mut iter = str.chars();
     let mut inside_str = false;
+    let mut depth = 0;
     let mut bob = String::new();
     while let Some(char) = iter.next() {
-        if char == ',' && !inside_str {
+        if !inside_str {
+            match char {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            }
+        }
+        if char == ',' && !inside_str && depth == 0 {
             arguments.push(bob.clone());
             bob.clear();
             continue;
@@ -222,7 +416,7 @@ fn transform_arguments(str: String) -> Vec<String> {
             bob.push(iter.next().unwrap());
         }
     }
-    if bob.len() != 0 {
+    if !bob.is_empty() {
         arguments.push(bob);
     }
 
@@ -249,7 +443,7 @@ impl Node {
                 print!("{} ", element);
                 sub_lst.print();
             }
-            _ => println!(""),
+            _ => println!(),
         }
     }
 }
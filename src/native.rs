@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::ErrorKind;
+use crate::value::Value;
+use crate::vm::VM;
+
+/// Rust function callable from Atic code. It receives exactly as many arguments as it
+/// was registered with and can use the [VM] to read and allocate objects.
+pub type NativeFunction = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, ErrorKind>>;
+
+pub struct Native {
+    pub name: String,
+    pub args: usize,
+    pub function: NativeFunction,
+}
+
+/// Host functions available to `extern fn` declarations, looked up by name while
+/// linking.
+#[derive(Default)]
+pub struct Natives {
+    natives: Vec<Native>,
+    indices: HashMap<String, usize>,
+}
+
+impl Natives {
+    /// Registers `function` under `name`, replacing an earlier function of that name.
+    pub fn register(
+        &mut self,
+        name: &str,
+        args: usize,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, ErrorKind> + 'static,
+    ) {
+        let native = Native {
+            name: name.to_string(),
+            args,
+            function: Rc::new(function),
+        };
+        match self.indices.get(name) {
+            Some(index) => self.natives[*index] = native,
+            None => {
+                self.indices.insert(name.to_string(), self.natives.len());
+                self.natives.push(native);
+            }
+        }
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    pub fn get(&self, index: usize) -> &Native {
+        &self.natives[index]
+    }
+}
+
+/// Registers the `Sys` functions every program can declare.
+pub fn register_system(natives: &mut Natives) {
+    natives.register("Sys.print", 1, |vm, args| {
+        println!("{}", vm.text_of(args[0])?);
+        Ok(Value::NULL)
+    });
+}
@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use num_format::{Grouping, Locale};
+use regex::Regex;
+
+use crate::error::ErrorKind;
+use crate::native::Natives;
+use crate::value::{Kind, Value};
+use crate::vm::VM;
+
+/// Most digits `Std.Format` functions print after the decimal point.
+const MAX_DIGITS: usize = 100;
+
+/// Compiled patterns kept by the `Std.Regex` functions; the cache starts over when full.
+const MAX_CACHED_PATTERNS: usize = 64;
+
+/// Registers the standard library under `Std.String`, `Std.Math`, `Std.Number`,
+/// `Std.Format` and `Std.Regex`.
+///
+/// Strings are indexed by character, not by byte.
+pub fn register_std(natives: &mut Natives) {
+    register_string(natives);
+    register_math(natives);
+    register_number(natives);
+    register_format(natives);
+    register_regex(natives);
+}
+
+fn register_string(natives: &mut Natives) {
+    natives.register("Std.String.len", 1, |vm, args| {
+        Ok(Value::number(vm.string(args[0])?.chars().count() as f64))
+    });
+    // Characters from `start` up to, but not including, `end`
+    natives.register("Std.String.substring", 3, |vm, args| {
+        let text = vm.string(args[0])?;
+        let length = text.chars().count();
+        let start = index(args[1], length)?;
+        let end = index(args[2], length)?;
+        if start > end {
+            return Err(ErrorKind::InvalidArgument(format!(
+                "substring starts at {}, after its end {}",
+                start, end
+            )));
+        }
+        let text = text.chars().skip(start).take(end - start).collect();
+        Ok(vm.new_string(text))
+    });
+    // Character position of the first occurrence, or -1
+    natives.register("Std.String.indexOf", 2, |vm, args| {
+        let text = vm.string(args[0])?;
+        let position = match text.find(vm.string(args[1])?) {
+            Some(byte) => text[..byte].chars().count() as f64,
+            None => -1.0,
+        };
+        Ok(Value::number(position))
+    });
+    natives.register("Std.String.split", 2, |vm, args| {
+        let separator = vm.string(args[1])?;
+        if separator.is_empty() {
+            return Err(ErrorKind::InvalidArgument(
+                "split needs a non-empty separator".to_string(),
+            ));
+        }
+        let parts: Vec<String> = vm
+            .string(args[0])?
+            .split(separator)
+            .map(str::to_string)
+            .collect();
+        let parts = parts.into_iter().map(|part| vm.new_string(part)).collect();
+        Ok(vm.new_array(parts))
+    });
+    natives.register("Std.String.upper", 1, |vm, args| {
+        let text = vm.string(args[0])?.to_uppercase();
+        Ok(vm.new_string(text))
+    });
+    natives.register("Std.String.lower", 1, |vm, args| {
+        let text = vm.string(args[0])?.to_lowercase();
+        Ok(vm.new_string(text))
+    });
+}
+
+type Unary = fn(f64) -> f64;
+type Binary = fn(f64, f64) -> f64;
+
+fn register_math(natives: &mut Natives) {
+    let unary: [(&str, Unary); 10] = [
+        ("Std.Math.sqrt", f64::sqrt),
+        ("Std.Math.sin", f64::sin),
+        ("Std.Math.cos", f64::cos),
+        ("Std.Math.tan", f64::tan),
+        ("Std.Math.asin", f64::asin),
+        ("Std.Math.acos", f64::acos),
+        ("Std.Math.atan", f64::atan),
+        ("Std.Math.floor", f64::floor),
+        ("Std.Math.ceil", f64::ceil),
+        // Halfway cases round away from zero
+        ("Std.Math.round", f64::round),
+    ];
+    for (name, function) in unary {
+        natives.register(name, 1, move |_, args| {
+            Ok(Value::number(function(number(args[0])?)))
+        });
+    }
+
+    let binary: [(&str, Binary); 4] = [
+        ("Std.Math.pow", f64::powf),
+        ("Std.Math.atan2", f64::atan2),
+        ("Std.Math.min", f64::min),
+        ("Std.Math.max", f64::max),
+    ];
+    for (name, function) in binary {
+        natives.register(name, 2, move |_, args| {
+            Ok(Value::number(function(number(args[0])?, number(args[1])?)))
+        });
+    }
+
+    // Euclidean remainder, never negative, unlike the sign-following `%` of Rust
+    natives.register("Std.Math.mod", 2, |_, args| {
+        let divisor = number(args[1])?;
+        if divisor == 0.0 {
+            return Err(ErrorKind::DivisionByZero);
+        }
+        Ok(Value::number(number(args[0])?.rem_euclid(divisor)))
+    });
+}
+
+fn register_number(natives: &mut Natives) {
+    // Same text as `Debug` and `Concat` produce
+    natives.register("Std.Number.toString", 1, |vm, args| {
+        let text = number(args[0])?.to_string();
+        Ok(vm.new_string(text))
+    });
+    // Null if the string is not a number
+    natives.register("Std.Number.parse", 1, |vm, args| {
+        match vm.string(args[0])?.trim().parse::<f64>() {
+            Ok(number) => Ok(Value::number(number)),
+            Err(_) => Ok(Value::NULL),
+        }
+    });
+}
+
+fn number(value: Value) -> Result<f64, ErrorKind> {
+    value.as_number().ok_or(ErrorKind::TypeMismatch {
+        expected: Kind::Number,
+        found: value.kind(),
+    })
+}
+
+/// Character position for `substring`; the length itself is valid as an end.
+fn index(value: Value, length: usize) -> Result<usize, ErrorKind> {
+    let index = number(value)?;
+    if !(index >= 0.0 && index.fract() == 0.0 && index as usize <= length) {
+        return Err(ErrorKind::InvalidArgument(format!(
+            "index {} is out of bounds for a string of length {}",
+            index, length
+        )));
+    }
+    Ok(index as usize)
+}
+
+/// Formatting for reports. `digits` arguments are the number of digits after the
+/// decimal point, from 0 to [MAX_DIGITS].
+fn register_format(natives: &mut Natives) {
+    // Thousands separators and decimal point of a locale such as "en", "de" or "fr".
+    // With null digits the fraction is kept as `Debug` prints it.
+    natives.register("Std.Format.grouped", 3, |vm, args| {
+        let number = number(args[0])?;
+        let name = vm.string(args[1])?;
+        let locale = Locale::from_name(name)
+            .map_err(|_| ErrorKind::InvalidArgument(format!("unknown locale {}", name)))?;
+        if !number.is_finite() {
+            return Ok(vm.new_string(number.to_string()));
+        }
+        let text = match args[2] {
+            Value::NULL => number.abs().to_string(),
+            value => format!("{:.*}", digits(value)?, number.abs()),
+        };
+        let (integer, fraction) = match text.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (text.as_str(), None),
+        };
+
+        // No sign if every printed digit is zero, like -0.001 with 2 digits
+        let mut grouped = String::new();
+        if number < 0.0 && text.bytes().any(|digit| matches!(digit, b'1'..=b'9')) {
+            grouped += locale.minus_sign();
+        }
+        grouped += &group(integer, &locale);
+        if let Some(fraction) = fraction {
+            grouped += locale.decimal();
+            grouped += fraction;
+        }
+        Ok(vm.new_string(grouped))
+    });
+    // Rounded to exactly `digits` decimals, like "3.14" for 2
+    natives.register("Std.Format.fixed", 2, |vm, args| {
+        let text = format!("{:.*}", digits(args[1])?, number(args[0])?);
+        Ok(vm.new_string(text))
+    });
+    // Mantissa with `digits` decimals and an exponent, like "1.23e4" for 2
+    natives.register("Std.Format.scientific", 2, |vm, args| {
+        let text = format!("{:.*e}", digits(args[1])?, number(args[0])?);
+        Ok(vm.new_string(text))
+    });
+}
+
+/// Patterns are either regex values from `Std.Regex.compile` or strings, which are
+/// compiled on first use and cached. Invalid patterns throw their error message.
+fn register_regex(natives: &mut Natives) {
+    let cache = Rc::new(RefCell::new(PatternCache::default()));
+
+    let patterns = cache.clone();
+    natives.register("Std.Regex.compile", 1, move |vm, args| {
+        let regex = patterns.borrow_mut().get(vm.string(args[0])?)?;
+        Ok(vm.new_regex(regex))
+    });
+    let patterns = cache.clone();
+    natives.register("Std.Regex.test", 2, move |vm, args| {
+        let regex = pattern(vm, &patterns, args[0])?;
+        let found = regex.is_match(vm.string(args[1])?);
+        Ok(Value::number(if found { 1.0 } else { 0.0 }))
+    });
+    // Array with one array per match: the whole match, then every capture group, which
+    // is null if the group did not take part in the match
+    let patterns = cache.clone();
+    natives.register("Std.Regex.findAll", 2, move |vm, args| {
+        let regex = pattern(vm, &patterns, args[0])?;
+        let text = vm.string(args[1])?;
+        let found: Vec<Vec<Option<String>>> = regex
+            .captures_iter(text)
+            .map(|captures| {
+                captures
+                    .iter()
+                    .map(|group| group.map(|group| group.as_str().to_string()))
+                    .collect()
+            })
+            .collect();
+        let matches = found
+            .into_iter()
+            .map(|groups| {
+                let groups = groups
+                    .into_iter()
+                    .map(|group| match group {
+                        Some(group) => vm.new_string(group),
+                        None => Value::NULL,
+                    })
+                    .collect();
+                vm.new_array(groups)
+            })
+            .collect();
+        Ok(vm.new_array(matches))
+    });
+    // The replacement can refer to groups as `$1` or `${name}`
+    let patterns = cache;
+    natives.register("Std.Regex.replaceAll", 3, move |vm, args| {
+        let regex = pattern(vm, &patterns, args[0])?;
+        let text = regex
+            .replace_all(vm.string(args[1])?, vm.string(args[2])?)
+            .into_owned();
+        Ok(vm.new_string(text))
+    });
+}
+
+#[derive(Default)]
+struct PatternCache {
+    patterns: HashMap<String, Rc<Regex>>,
+}
+
+impl PatternCache {
+    fn get(&mut self, pattern: &str) -> Result<Rc<Regex>, ErrorKind> {
+        if let Some(regex) = self.patterns.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Rc::new(
+            Regex::new(pattern).map_err(|error| ErrorKind::InvalidPattern(error.to_string()))?,
+        );
+        if self.patterns.len() >= MAX_CACHED_PATTERNS {
+            self.patterns.clear();
+        }
+        self.patterns.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+}
+
+/// The regex in `value`, compiling it through `cache` if it is a pattern string. It is
+/// shared rather than copied, and does not borrow `vm`, so natives can still allocate.
+fn pattern(vm: &VM, cache: &RefCell<PatternCache>, value: Value) -> Result<Rc<Regex>, ErrorKind> {
+    match value.kind() {
+        Kind::String => cache.borrow_mut().get(vm.string(value)?),
+        _ => Ok(vm.regex(value)?.clone()),
+    }
+}
+
+/// Inserts the thousands separators of `locale` into a string of decimal digits.
+fn group(digits: &str, locale: &Locale) -> String {
+    let (first, rest) = match locale.grouping() {
+        Grouping::Standard => (3, 3),
+        Grouping::Indian => (3, 2),
+        Grouping::Posix => return digits.to_string(),
+    };
+    let mut groups = vec![];
+    let mut end = digits.len();
+    let mut size = first;
+    while end > size {
+        groups.push(&digits[end - size..end]);
+        end -= size;
+        size = rest;
+    }
+    groups.push(&digits[..end]);
+    groups.reverse();
+    groups.join(locale.separator())
+}
+
+fn digits(value: Value) -> Result<usize, ErrorKind> {
+    let digits = number(value)?;
+    if !(digits >= 0.0 && digits.fract() == 0.0 && digits as usize <= MAX_DIGITS) {
+        return Err(ErrorKind::InvalidArgument(format!(
+            "{} digits, but only 0 to {} are supported",
+            digits, MAX_DIGITS
+        )));
+    }
+    Ok(digits as usize)
+}
@@ -0,0 +1,138 @@
+use crate::heap::Handle;
+
+/// A value in a register, struct field, array element or capture.
+///
+/// Numbers are stored as their bits and need no unboxing. Everything else is NaN-boxed:
+/// a negative quiet NaN with its [Kind] in bits 47 to 50 and a heap [Handle] or callable
+/// index in the lower 47 bits. NaN results are stored as the positive canonical NaN, so no number
+/// can ever be mistaken for a boxed value.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Value(u64);
+
+const BOXED: u64 = 0xFFF8_0000_0000_0000;
+const KIND_SHIFT: u64 = 47;
+const KIND_MASK: u64 = 0xF << KIND_SHIFT;
+const PAYLOAD_MASK: u64 = (1 << KIND_SHIFT) - 1;
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Number,
+    Null,
+    String,
+    Function,
+    Struct,
+    Array,
+    Enum,
+    Closure,
+    Cell,
+    Regex,
+}
+
+impl Kind {
+    fn tag(self) -> u64 {
+        match self {
+            Kind::Null => 0,
+            Kind::String => 1,
+            Kind::Function => 2,
+            Kind::Struct => 3,
+            Kind::Array => 4,
+            Kind::Enum => 5,
+            Kind::Closure => 6,
+            Kind::Cell => 7,
+            Kind::Regex => 8,
+            Kind::Number => unreachable!("Numbers are not boxed"),
+        }
+    }
+
+    fn from_tag(tag: u64) -> Kind {
+        match tag {
+            0 => Kind::Null,
+            1 => Kind::String,
+            2 => Kind::Function,
+            3 => Kind::Struct,
+            4 => Kind::Array,
+            5 => Kind::Enum,
+            6 => Kind::Closure,
+            7 => Kind::Cell,
+            8 => Kind::Regex,
+            _ => unreachable!("Invalid kind tag {}", tag),
+        }
+    }
+
+    /// Name used in type errors, e.g. "expected a number, but got a string".
+    pub fn describe(self) -> &'static str {
+        match self {
+            Kind::Number => "a number",
+            Kind::Null => "null",
+            Kind::String => "a string",
+            Kind::Function => "a function",
+            Kind::Struct => "a struct",
+            Kind::Array => "an array",
+            Kind::Enum => "an enum entry",
+            Kind::Closure => "a closure",
+            Kind::Cell => "a cell",
+            Kind::Regex => "a regex",
+        }
+    }
+}
+
+impl Value {
+    pub const NULL: Value = Value(BOXED);
+
+    #[inline(always)]
+    pub fn number(value: f64) -> Value {
+        if value.is_nan() {
+            Value(CANONICAL_NAN)
+        } else {
+            Value(value.to_bits())
+        }
+    }
+
+    pub fn boxed(kind: Kind, index: usize) -> Value {
+        debug_assert!(index as u64 <= PAYLOAD_MASK);
+        Value(BOXED | kind.tag() << KIND_SHIFT | index as u64)
+    }
+
+    #[inline(always)]
+    fn is_boxed(self) -> bool {
+        self.0 >= BOXED
+    }
+
+    pub fn kind(self) -> Kind {
+        if self.is_boxed() {
+            Kind::from_tag((self.0 & KIND_MASK) >> KIND_SHIFT)
+        } else {
+            Kind::Number
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_number(self) -> Option<f64> {
+        if self.is_boxed() {
+            None
+        } else {
+            Some(f64::from_bits(self.0))
+        }
+    }
+
+    pub fn object(kind: Kind, handle: Handle) -> Value {
+        Value::boxed(kind, handle.payload())
+    }
+
+    /// Callable index of a function, or the payload of any other boxed value.
+    pub fn index(self) -> usize {
+        (self.0 & PAYLOAD_MASK) as usize
+    }
+
+    /// Heap handle of a boxed object.
+    pub fn handle(self) -> Handle {
+        Handle::from_payload(self.index())
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::number(0.0)
+    }
+}
@@ -1,186 +1,1124 @@
-use std::{
-    alloc::Layout,
-    cell::Ref,
-    collections::LinkedList,
-    fmt::Debug,
-    mem::{size_of, ManuallyDrop, MaybeUninit},
-    ptr,
-    rc::Rc,
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::error::{ErrorKind, RuntimeError};
+use crate::heap::{GcStats, Heap, HeapObject};
+use crate::linker::{
+    Handler, Instruction, Instruction::*, JumpTable, Offset, Program, Register, SourceMap,
 };
+use crate::native::Natives;
+use crate::value::{Kind, Value};
 
-use crate::{linker::Instruction, linker::Instruction::*};
+/// How deeply nested structs are printed by `Debug` and converted by `Concat`.
+const MAX_PRINT_DEPTH: usize = 8;
 
-#[derive(Clone, Copy)]
-union AticObj<'v> {
-    as_number: f64,
-    as_text: &'v String,
-    as_object: &'v Box<[AticObj<'v>]>,
-}
+/// Words saved on `call_stack` per call: return adress, caller frame pointer,
+/// caller frame size, the register receiving the return value and the caller's closure.
+const FRAME_SIZE: usize = 5;
+
+/// Value of `VM::closure` while running a plain function.
+const NO_CLOSURE: usize = usize::MAX;
+
+/// Default limits of [VM::set_stack_limits]: nested calls and registers of all frames.
+const DEFAULT_MAX_CALLS: usize = 100_000;
+const DEFAULT_MAX_VALUES: usize = 1 << 22;
 
-pub struct VM<'a> {
+/// Largest array `CreateArraySized` allocates, in elements.
+const MAX_ARRAY_SIZE: usize = 1 << 24;
+
+/// Initial size of `stack` in registers; it grows on demand.
+const INITIAL_STACK_SIZE: usize = 1024;
+
+/// Size of `call_buffer`, enough for every [crate::linker::Offset] an `Argument` can target.
+const CALL_BUFFER_SIZE: usize = Register::MAX as usize + 1;
+
+pub struct VM {
     instructions: Box<[Instruction]>,
-    stack: Box<[AticObj<'a>; 10000]>,
-    call_buffer: Box<[AticObj<'a>; 255]>,
-    call_stack: Box<[usize; 10000]>,
+    constants: Box<[f64]>,
+    callables: Box<[Callable]>,
+    natives: Natives,
+    jump_tables: Box<[JumpTable]>,
+    handlers: Box<[Handler]>,
+    source_map: SourceMap,
+    stack: Vec<Value>,
+    call_buffer: Box<[Value]>,
+    /// Objects allocated by the running native function, kept alive until it returns
+    native_roots: Vec<Value>,
+    /// Number of `call_buffer` entries written since the last call or allocation
+    /// consumed them; only these are roots for the collector
+    staged: usize,
+    call_stack: Vec<usize>,
+    max_calls: usize,
+    max_values: usize,
+    interrupt: InterruptHandle,
     activation_record_pointer: usize,
     stack_pointer: usize,
-    object_list: LinkedList<Box<[AticObj<'a>]>>,
+    /// Starts out with the program's string pool, so `LoadString` never allocates
+    heap: Heap,
     active_record_size: usize,
+    /// Heap index of the running closure, [NO_CLOSURE] for plain functions
+    closure: usize,
     pc: usize,
     running: bool,
     pub dbg_iter: i64,
     exit_code: f64,
 }
 
-impl VM<'_> {
-    pub fn new(instructions: Vec<Instruction>) -> VM<'static> {
+impl VM {
+    pub fn new(program: Program, natives: Natives) -> VM {
         println!("Starting vm....");
-        unsafe {
-            let callbuffer = std::alloc::alloc_zeroed(Layout::new::<[AticObj; 255]>());
-            let callbuffer = (callbuffer as *mut [AticObj; 255]);
+        VM {
+            instructions: program.instructions,
+            constants: program.constants,
+            callables: program.callables,
+            natives,
+            jump_tables: program.jump_tables,
+            handlers: program.handlers,
+            source_map: program.source_map,
+            stack: vec![Value::default(); INITIAL_STACK_SIZE],
+            call_buffer: vec![Value::NULL; CALL_BUFFER_SIZE].into_boxed_slice(),
+            native_roots: vec![],
+            call_stack: vec![],
+            max_calls: DEFAULT_MAX_CALLS,
+            max_values: DEFAULT_MAX_VALUES,
+            interrupt: InterruptHandle::default(),
+            activation_record_pointer: 0,
+            staged: 0,
+            heap: Heap::new(program.strings.into_vec()),
+            stack_pointer: 0,
+            active_record_size: 0,
+            closure: NO_CLOSURE,
+            pc: 0,
+            running: true,
+            dbg_iter: 0,
+            exit_code: 0.0,
+        }
+    }
 
-            let array = std::alloc::alloc_zeroed(Layout::new::<[AticObj; 10000]>());
-            let array = (array as *mut [AticObj; 10000]);
+    /// Limits how deep calls can nest and how many registers all frames together can
+    /// use before execution stops with [ErrorKind::StackOverflow].
+    pub fn set_stack_limits(&mut self, calls: usize, values: usize) {
+        self.max_calls = calls;
+        self.max_values = values;
+    }
 
-            let call_stack = std::alloc::alloc_zeroed(Layout::new::<[usize; 10000]>());
-            let call_stack = (call_stack as *mut [usize; 10000]);
+    /// Enters the function at `adress` with a frame of `registers` null registers.
+    /// Returning from it ends the program. Fails if the frame alone exceeds the stack
+    /// limits.
+    pub fn start(&mut self, adress: usize, registers: usize) -> Result<(), RuntimeError> {
+        println!("Starting vm....");
 
-            VM {
-                instructions: instructions.into_boxed_slice(),
-                stack: Box::new(*array),
-                call_buffer: Box::new(*callbuffer),
-                call_stack: Box::new(*call_stack),
-                activation_record_pointer: 0,
-                object_list: LinkedList::new(),
-                stack_pointer: 0,
-                active_record_size: 0,
-                pc: 0,
-                running: true,
-                dbg_iter: 0,
-                exit_code: 0.0,
-            }
+        self.stack_pointer = 0;
+        self.activation_record_pointer = 0;
+        self.pc = adress;
+        if registers > self.max_values {
+            return Err(self.error(ErrorKind::StackOverflow {
+                calls: self.max_calls,
+                values: self.max_values,
+            }));
+        }
+        self.active_record_size = registers;
+        if self.stack.len() < registers {
+            self.stack.resize(registers, Value::default());
         }
+        self.stack[..registers].fill(Value::NULL);
+        Ok(())
     }
-    pub fn start(&mut self, adress: usize, arg_size: usize) {
-        println!("Starting vm....");
 
-        self.pc += 1;
-        self.call_stack[0] = (self.pc + 1) as usize;
-        self.call_stack[1] = self.active_record_size as usize;
-        self.call_stack[2] = 0;
-        self.activation_record_pointer += self.active_record_size;
-        self.stack_pointer += 3;
-        self.active_record_size += arg_size;
-        self.pc = adress;
-        println!("Size {}", size_of::<Box<AticObj>>());
-        println!("Size {}", size_of::<Rc<AticObj>>());
+    /// Executes up to `budget` instructions. Running out of budget leaves the VM ready to
+    /// continue with another call; once the program has exited or failed, further calls
+    /// return [RunOutcome::Exited] with the final exit code.
+    pub fn run(&mut self, budget: u64) -> RunOutcome {
+        let mut steps = 0;
+        while self.running {
+            if steps == budget {
+                return RunOutcome::OutOfBudget;
+            }
+            steps += 1;
+            match self.tick() {
+                Ok(()) => {}
+                Err(Halt::Interrupted) => {
+                    self.interrupt.clear();
+                    return RunOutcome::Interrupted;
+                }
+                Err(Halt::Error(error)) => {
+                    self.running = false;
+                    self.exit_code = 1.0;
+                    return RunOutcome::Failed(error);
+                }
+            }
+        }
+        RunOutcome::Exited(self.exit_code)
     }
 
-    pub fn tick(&mut self) {
-        println!("Ticking VM");
+    #[inline(always)]
+    fn tick(&mut self) -> Result<(), Halt> {
         self.dbg_iter += 1;
-        let instruction = &self.instructions[self.pc];
+        let instruction = self.instructions[self.pc];
 
         match instruction {
             Argument(index, src) => {
-                self.call_buffer[*index as usize] =
-                    self.stack[self.active_record_size + (*src as usize)];
+                self.call_buffer[index as usize] = self.get(src);
+                self.staged = self.staged.max(index as usize + 1);
                 self.pc += 1;
             }
             CreateStruct(register, size) => {
-                let mut obj = Vec::with_capacity(*size as usize);
-                // for i in 0..*size {
-                //     obj.push(AticObj{as_number: 0.0});
-                // }\
-                unsafe {
-                    core::ptr::copy(self.call_buffer.as_ptr(), obj.as_mut_ptr(), *size as usize);
-                    let obj_a = std::alloc::alloc_zeroed(Layout::new::<Box<AticObj>>());
-                    let pt = (obj_a as *mut Box<AticObj<'static>>);
-                    let box_ = obj.into_boxed_slice();
-                    
-                  //  let obj_a = (array as *mut [AticObj; 10000]);
+                let fields = self.staged_values("CreateStruct", size)?;
+                let value =
+                    self.allocate(HeapObject::Struct(fields.into_boxed_slice()), Kind::Struct);
+                self.set(register, value);
+                self.staged = 0;
+                self.pc += 1;
+            }
+            LoadMember(target, object, offset) => {
+                let field = *self.member(object, offset)?;
+                self.set(target, field);
+                self.pc += 1;
+            }
+            StoreMember(object, src, offset) => {
+                let value = self.get(src);
+                *self.member(object, offset)? = value;
+                self.pc += 1;
+            }
+            Nop => {
+                self.pc += 1;
+            }
+            CreateArray(register, size) => {
+                let elements = self.staged_values("CreateArray", size)?;
+                let value = self.allocate(HeapObject::Array(elements), Kind::Array);
+                self.set(register, value);
+                self.staged = 0;
+                self.pc += 1;
+            }
+            CreateArraySized(register, size) => {
+                let size = self.number(size)?;
+                if !(size >= 0.0 && size.fract() == 0.0 && size <= MAX_ARRAY_SIZE as f64) {
+                    return Err(self.error(ErrorKind::InvalidArraySize(size)).into());
                 }
-
-                self.stack[self.activation_record_pointer + *register as usize] =
-                    AticObj { as_object: &box_ };
-
-                self.object_list.push_front(box_);
+                let elements = vec![Value::number(0.0); size as usize];
+                let value = self.allocate(HeapObject::Array(elements), Kind::Array);
+                self.set(register, value);
+                self.pc += 1;
             }
-            Debug(register) => {
-                let object = &self.stack[(self.activation_record_pointer + (*register) as usize)];
-                unsafe {
-                    println!("> {}: {}", register, object.as_number);
+            LoadArray(target, array, index) => {
+                let element = *self.element(array, index)?;
+                self.set(target, element);
+                self.pc += 1;
+            }
+            StoreArray(array, src, index) => {
+                let value = self.get(src);
+                *self.element(array, index)? = value;
+                self.pc += 1;
+            }
+            ArrayLength(target, array) => {
+                let length = self.array(array)?.len();
+                self.set_number(target, length as f64);
+                self.pc += 1;
+            }
+            ArrayPush(array, src) => {
+                let value = self.get(src);
+                self.array(array)?.push(value);
+                self.pc += 1;
+            }
+            ArrayPop(target, array) => {
+                let value = match self.array(array)?.pop() {
+                    Some(value) => value,
+                    None => return Err(self.error(ErrorKind::EmptyArray).into()),
+                };
+                self.set(target, value);
+                self.pc += 1;
+            }
+            CreateEnumEntry(register, tag, size) => {
+                let fields = self.staged_values("CreateEnumEntry", size)?;
+                let value =
+                    self.allocate(HeapObject::Enum(tag, fields.into_boxed_slice()), Kind::Enum);
+                self.set(register, value);
+                self.staged = 0;
+                self.pc += 1;
+            }
+            LoadEnumType(target, src) => {
+                let tag = self.enum_entry(src)?.0;
+                self.set_number(target, tag as f64);
+                self.pc += 1;
+            }
+            LoadEnumMember(target, src, offset) => {
+                let field = *self.enum_member(src, offset)?;
+                self.set(target, field);
+                self.pc += 1;
+            }
+            CopyEnumMember(target, src, offset) => {
+                let value = self.get(src);
+                *self.enum_member(target, offset)? = value;
+                self.pc += 1;
+            }
+            CreateClosure(register, size) => {
+                let function = self.get(register);
+                self.expect(function, Kind::Function)?;
+                let callable = &self.callables[function.index()];
+                if callable.capture_size != size as i32 {
+                    return Err(self
+                        .error(ErrorKind::CaptureCount {
+                            function: callable.name.clone(),
+                            expected: callable.capture_size as usize,
+                            found: size as usize,
+                        })
+                        .into());
                 }
+                let captures = self.staged_values("CreateClosure", size)?;
+                let value = self.allocate(
+                    HeapObject::Closure(function.index(), captures.into_boxed_slice()),
+                    Kind::Closure,
+                );
+                self.set(register, value);
+                self.staged = 0;
+                self.pc += 1;
+            }
+            LoadCapture(target, offset) => {
+                let capture = *self.capture(offset)?;
+                self.set(target, capture);
+                self.pc += 1;
+            }
+            CreateCell(target, src) => {
+                let value = self.get(src);
+                let value = self.allocate(HeapObject::Cell(value), Kind::Cell);
+                self.set(target, value);
+                self.pc += 1;
+            }
+            LoadCell(target, cell) => {
+                let value = *self.cell(cell)?;
+                self.set(target, value);
+                self.pc += 1;
+            }
+            StoreCell(cell, src) => {
+                let value = self.get(src);
+                *self.cell(cell)? = value;
+                self.pc += 1;
+            }
+            Copy(target, src) => {
+                self.set(target, self.get(src));
+                self.pc += 1;
+            }
+            Debug(register) => {
+                let value = self.get(register);
+                println!("> {}: {}", register, self.text(value)?);
+                self.pc += 1;
+            }
+            LoadString(target, string) => {
+                self.set(target, Value::boxed(Kind::String, string as usize));
+                self.pc += 1;
+            }
+            Concat(target, src_a, src_b) => {
+                let mut text = self.text(self.get(src_a))?;
+                text += &self.text(self.get(src_b))?;
+                let value = self.allocate(HeapObject::Text(text), Kind::String);
+                self.set(target, value);
+                self.pc += 1;
+            }
+            StringEquals(target, src_a, src_b) => {
+                let equal = self.text(self.get(src_a))? == self.text(self.get(src_b))?;
+                self.set_bool(target, equal);
+                self.pc += 1;
+            }
+            StringNonEquals(target, src_a, src_b) => {
+                let equal = self.text(self.get(src_a))? == self.text(self.get(src_b))?;
+                self.set_bool(target, !equal);
                 self.pc += 1;
             }
             Add(target, src_a, src_b) => {
-                let s1 = &self.stack[(self.activation_record_pointer + (*src_a) as usize)];
-                let s2 = &self.stack[(self.activation_record_pointer + (*src_b) as usize)];
-                unsafe {
-                    self.stack[(self.activation_record_pointer + (*target) as usize)] = AticObj {
-                        as_number: s1.as_number + s2.as_number,
-                    };
+                self.set_number(target, self.number(src_a)? + self.number(src_b)?);
+                self.pc += 1;
+            }
+            Subtract(target, src_a, src_b) => {
+                self.set_number(target, self.number(src_a)? - self.number(src_b)?);
+                self.pc += 1;
+            }
+            Multiply(target, src_a, src_b) => {
+                self.set_number(target, self.number(src_a)? * self.number(src_b)?);
+                self.pc += 1;
+            }
+            Divide(target, src_a, src_b) => {
+                let divisor = self.number(src_b)?;
+                if divisor == 0.0 {
+                    return Err(self.error(ErrorKind::DivisionByZero).into());
                 }
+                self.set_number(target, self.number(src_a)? / divisor);
+                self.pc += 1;
+            }
+            Negate(target, src) => {
+                self.set_number(target, -self.number(src)?);
+                self.pc += 1;
+            }
+            Not(target, src) => {
+                self.set_bool(target, !self.truthy(src));
+                self.pc += 1;
+            }
+            Or(target, src_a, src_b) => {
+                self.set_bool(target, self.truthy(src_a) || self.truthy(src_b));
+                self.pc += 1;
+            }
+            And(target, src_a, src_b) => {
+                self.set_bool(target, self.truthy(src_a) && self.truthy(src_b));
                 self.pc += 1;
             }
             Smaller(target, src_a, src_b) => {
-                let s1 = &self.stack[(self.activation_record_pointer + (*src_a) as usize)];
-                let s2 = &self.stack[(self.activation_record_pointer + (*src_b) as usize)];
-
-                unsafe {
-                    self.stack[(self.activation_record_pointer + *target as usize)] = AticObj {
-                        as_number: if s1.as_number < s2.as_number {
-                            1.0
-                        } else {
-                            0.0
-                        },
-                    };
-                }
+                self.set_bool(target, self.number(src_a)? < self.number(src_b)?);
+                self.pc += 1;
+            }
+            SmallerEq(target, src_a, src_b) => {
+                self.set_bool(target, self.number(src_a)? <= self.number(src_b)?);
+                self.pc += 1;
+            }
+            Greater(target, src_a, src_b) => {
+                self.set_bool(target, self.number(src_a)? > self.number(src_b)?);
+                self.pc += 1;
+            }
+            GreaterEq(target, src_a, src_b) => {
+                self.set_bool(target, self.number(src_a)? >= self.number(src_b)?);
+                self.pc += 1;
+            }
+            Equals(target, src_a, src_b) => {
+                self.set_bool(target, self.equals(src_a, src_b));
+                self.pc += 1;
+            }
+            NonEquals(target, src_a, src_b) => {
+                self.set_bool(target, !self.equals(src_a, src_b));
+                self.pc += 1;
+            }
+            LoadNull(target) => {
+                self.set(target, Value::NULL);
                 self.pc += 1;
             }
             LoadConst(target, constant) => {
-                self.stack[(self.activation_record_pointer + *target as usize)] = AticObj {
-                    as_number: *constant,
-                };
+                self.set_number(target, self.constants[constant as usize]);
                 self.pc += 1;
             }
             Jump(target) => {
-                self.pc = target.adress as usize;
+                self.jump(target as usize)?;
             }
             JumpIfNot(register, target) => {
-                let s1 = &self.stack[(self.activation_record_pointer + *register as usize)];
-                unsafe {
-                    if s1.as_number < 0.5 {
-                        self.pc = target.adress as usize;
-                    } else {
-                        self.pc += 1;
+                if self.truthy(register) {
+                    self.pc += 1;
+                } else {
+                    self.jump(target as usize)?;
+                }
+            }
+            JumpIf(register, target) => {
+                if self.truthy(register) {
+                    self.jump(target as usize)?;
+                } else {
+                    self.pc += 1;
+                }
+            }
+            Match(register, table) => {
+                let value = self.get(register);
+                let tag = match value.kind() {
+                    Kind::Enum => match self.object(value)? {
+                        HeapObject::Enum(tag, _) => *tag as usize,
+                        _ => unreachable!("Enum entries are boxed as Kind::Enum"),
+                    },
+                    // Plain numbers are matched by value, anything else takes the default arm
+                    Kind::Number => {
+                        let number = self.number(register)?;
+                        if number >= 0.0 && number.fract() == 0.0 {
+                            number as usize
+                        } else {
+                            usize::MAX
+                        }
+                    }
+                    _ => usize::MAX,
+                };
+                self.jump(self.jump_tables[table as usize].target(tag) as usize)?;
+            }
+            LoadFunction(target, callable) => {
+                self.set(target, Value::boxed(Kind::Function, callable as usize));
+                self.pc += 1;
+            }
+            InvokeFunction(target, function) => {
+                self.check_interrupt()?;
+                let value = self.get(function);
+                match value.kind() {
+                    Kind::Function => self.invoke(value.index(), NO_CLOSURE, target)?,
+                    Kind::Closure => match self.object(value)? {
+                        HeapObject::Closure(callable, _) => {
+                            self.invoke(*callable, value.index(), target)?
+                        }
+                        _ => unreachable!("Closures are boxed as Kind::Closure"),
+                    },
+                    _ => return Err(self.type_error(Kind::Function, value).into()),
+                }
+            }
+            Return(register) => {
+                let value = self.get(register);
+                if self.stack_pointer == 0 {
+                    // Returning from the entry function ends the program
+                    self.running = false;
+                    self.exit_code = match value.kind() {
+                        Kind::Null => 0.0,
+                        _ => self.expect_number(value)?,
                     };
+                    println!("Exit with code {}", self.exit_code);
+                    return Ok(());
                 }
+                self.stack_pointer -= FRAME_SIZE;
+                let frame = &self.call_stack[self.stack_pointer..self.stack_pointer + FRAME_SIZE];
+                self.pc = frame[0];
+                self.activation_record_pointer = frame[1];
+                self.active_record_size = frame[2];
+                let target = frame[3];
+                self.closure = frame[4];
+                self.stack[self.activation_record_pointer + target] = value;
+            }
+            Throw(register) => {
+                let value = self.get(register);
+                self.throw(value)?;
             }
             Exit(code) => {
                 self.running = false;
-                self.exit_code = *code;
-                println!("Exit with code {}", *code);
+                self.exit_code = self.constants[code as usize];
+                println!("Exit with code {}", self.exit_code);
             }
-            _ => {
-                panic!("Instruction not implemented");
+        }
+        Ok(())
+    }
+
+    /// Continues at `target`. Jumping backwards then checks for an interrupt, so every
+    /// loop can be stopped; an interrupted run resumes at `target`.
+    #[inline(always)]
+    fn jump(&mut self, target: usize) -> Result<(), Halt> {
+        let backwards = target <= self.pc;
+        self.pc = target;
+        if backwards {
+            self.check_interrupt()?;
+        }
+        Ok(())
+    }
+
+    /// Stops before the current instruction if the [InterruptHandle] was triggered, so
+    /// resuming executes it normally.
+    #[inline(always)]
+    fn check_interrupt(&self) -> Result<(), Halt> {
+        if self.interrupt.is_set() {
+            return Err(Halt::Interrupted);
+        }
+        Ok(())
+    }
+
+    /// Handle that stops [VM::run] from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// The first `size` staged values, for an instruction that builds an object from
+    /// them. Only staged entries of `call_buffer` are roots, so the rest may refer to
+    /// objects the collector already freed.
+    fn staged_values(
+        &self,
+        instruction: &'static str,
+        size: Offset,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let size = size as usize;
+        if size > self.staged {
+            return Err(self.error(ErrorKind::StagedCount {
+                instruction,
+                expected: size,
+                found: self.staged,
+            }));
+        }
+        Ok(self.call_buffer[..size].to_vec())
+    }
+
+    /// Calls `callable` with the arguments staged in `call_buffer`. Its frame starts
+    /// right after the caller's registers, with every register past the arguments null;
+    /// the caller's state is saved on `call_stack` and restored by `Return`, which
+    /// writes the result into `target`.
+    fn invoke(&mut self, callable: usize, closure: usize, target: Register) -> Result<(), Halt> {
+        let frame = self.activation_record_pointer + self.active_record_size;
+        let end = frame + self.callables[callable].registers as usize;
+        if end > self.stack.len()
+            || end > self.max_values
+            || self.stack_pointer + FRAME_SIZE > self.call_stack.len()
+        {
+            self.grow_stacks(end)?;
+        }
+        let callable = &self.callables[callable];
+        if let Some(native) = callable.native {
+            return self.invoke_native(native, target);
+        }
+        if callable.adress < 0 {
+            return Err(Halt::Error(
+                self.error(ErrorKind::UnknownCallable(callable.name.clone())),
+            ));
+        }
+        let args = callable.args as usize;
+        if self.staged != args {
+            return Err(Halt::Error(self.error(ErrorKind::ArgumentCount {
+                function: callable.name.clone(),
+                expected: args,
+                found: self.staged,
+            })));
+        }
+        self.stack[frame..frame + args].copy_from_slice(&self.call_buffer[..args]);
+        self.stack[frame + args..end].fill(Value::NULL);
+        self.staged = 0;
+
+        let saved = [
+            self.pc + 1,
+            self.activation_record_pointer,
+            self.active_record_size,
+            target as usize,
+            self.closure,
+        ];
+        self.call_stack[self.stack_pointer..self.stack_pointer + FRAME_SIZE]
+            .copy_from_slice(&saved);
+        self.stack_pointer += FRAME_SIZE;
+
+        self.activation_record_pointer = frame;
+        self.active_record_size = callable.registers as usize;
+        self.closure = closure;
+        self.pc = callable.adress as usize;
+        Ok(())
+    }
+
+    /// Calls a native function with the staged arguments and writes its result into
+    /// `target`. The arguments stay staged during the call, so they are still roots if
+    /// the function allocates. Catchable errors are thrown as their message, so `try`
+    /// blocks can handle them.
+    #[inline(never)]
+    fn invoke_native(&mut self, native: usize, target: Register) -> Result<(), Halt> {
+        let native = self.natives.get(native);
+        if self.staged != native.args {
+            return Err(Halt::Error(self.error(ErrorKind::ArgumentCount {
+                function: native.name.clone(),
+                expected: native.args,
+                found: self.staged,
+            })));
+        }
+        let function = native.function.clone();
+        let args = self.call_buffer[..native.args].to_vec();
+        let result = function(self, &args);
+        match result {
+            Ok(result) => {
+                self.native_roots.clear();
+                self.staged = 0;
+                self.set(target, result);
+                self.pc += 1;
+                Ok(())
+            }
+            Err(kind) if kind.is_catchable() => {
+                let message = self.new_string(kind.to_string());
+                self.native_roots.clear();
+                self.staged = 0;
+                self.throw(message)
+            }
+            Err(kind) => {
+                self.native_roots.clear();
+                Err(Halt::Error(self.error(kind)))
+            }
+        }
+    }
+
+    /// Makes room for one more call whose registers end at `end`, doubling the stacks
+    /// as needed. Also reached for any call past the limits, which is an overflow even
+    /// if the stacks already have room.
+    #[inline(never)]
+    fn grow_stacks(&mut self, end: usize) -> Result<(), RuntimeError> {
+        if end > self.max_values || self.stack_pointer / FRAME_SIZE >= self.max_calls {
+            return Err(self.error(ErrorKind::StackOverflow {
+                calls: self.max_calls,
+                values: self.max_values,
+            }));
+        }
+        if end > self.stack.len() {
+            let size = end.max(self.stack.len() * 2).min(self.max_values);
+            self.stack.resize(size, Value::default());
+        }
+        if self.stack_pointer + FRAME_SIZE > self.call_stack.len() {
+            let size = (self.call_stack.len() * 2).max(FRAME_SIZE * 64);
+            self.call_stack
+                .resize(size.min(self.max_calls * FRAME_SIZE), 0);
+        }
+        Ok(())
+    }
+
+    /// Error at the running instruction, with the current call chain as its trace.
+    #[cold]
+    #[inline(never)]
+    fn error(&self, kind: ErrorKind) -> RuntimeError {
+        RuntimeError {
+            kind,
+            trace: self.stack_trace(),
+        }
+    }
+
+    /// Source locations of the running instruction and of every pending call, innermost
+    /// first.
+    pub fn stack_trace(&self) -> Vec<String> {
+        let calls = self.call_stack[..self.stack_pointer]
+            .chunks(FRAME_SIZE)
+            .rev()
+            .map(|frame| self.source_map.describe(frame[0] - 1));
+        std::iter::once(self.source_map.describe(self.pc))
+            .chain(calls)
+            .collect()
+    }
+
+    #[inline(always)]
+    fn allocate(&mut self, object: HeapObject, kind: Kind) -> Value {
+        if self.heap.should_collect() {
+            self.collect(&object);
+        }
+        self.heap.insert(object, kind)
+    }
+
+    /// Collects garbage. Roots are the registers of every frame, the staged arguments,
+    /// objects allocated by a running native function, the closures of the running and
+    /// suspended calls and `pending`, the object about to be allocated.
+    #[inline(never)]
+    fn collect(&mut self, pending: &HeapObject) {
+        let closures = self.call_stack[..self.stack_pointer]
+            .chunks(FRAME_SIZE)
+            .map(|frame| frame[4])
+            .chain(std::iter::once(self.closure))
+            .filter(|closure| *closure != NO_CLOSURE)
+            .map(|closure| Value::boxed(Kind::Closure, closure));
+        let registers = &self.stack[..self.activation_record_pointer + self.active_record_size];
+        let roots = registers
+            .iter()
+            .chain(&self.call_buffer[..self.staged])
+            .chain(&self.native_roots)
+            .chain(pending.children())
+            .copied()
+            .chain(closures);
+        self.heap.collect(roots);
+    }
+
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    /// Text of `value` the way `Debug` prints it, for native functions.
+    pub fn text_of(&self, value: Value) -> Result<String, ErrorKind> {
+        self.text(value).map_err(|error| error.kind)
+    }
+
+    /// Contents of the string `value`, for native functions.
+    pub fn string(&self, value: Value) -> Result<&str, ErrorKind> {
+        if value.kind() != Kind::String {
+            return Err(ErrorKind::TypeMismatch {
+                expected: Kind::String,
+                found: value.kind(),
+            });
+        }
+        match self.object(value).map_err(|error| error.kind)? {
+            HeapObject::Text(text) => Ok(text),
+            _ => unreachable!("Strings are boxed as Kind::String"),
+        }
+    }
+
+    /// Allocates a string for a native function to return.
+    pub fn new_string(&mut self, text: String) -> Value {
+        let value = self.allocate(HeapObject::Text(text), Kind::String);
+        self.native_roots.push(value);
+        value
+    }
+
+    /// The compiled pattern `value`, for native functions.
+    pub fn regex(&self, value: Value) -> Result<&Rc<Regex>, ErrorKind> {
+        if value.kind() != Kind::Regex {
+            return Err(ErrorKind::TypeMismatch {
+                expected: Kind::Regex,
+                found: value.kind(),
+            });
+        }
+        match self.object(value).map_err(|error| error.kind)? {
+            HeapObject::Regex(regex) => Ok(regex),
+            _ => unreachable!("Regexes are boxed as Kind::Regex"),
+        }
+    }
+
+    /// Allocates a compiled pattern for a native function to return.
+    pub fn new_regex(&mut self, regex: Rc<Regex>) -> Value {
+        let value = self.allocate(HeapObject::Regex(regex), Kind::Regex);
+        self.native_roots.push(value);
+        value
+    }
+
+    /// Allocates an array for a native function to return.
+    pub fn new_array(&mut self, elements: Vec<Value>) -> Value {
+        let value = self.allocate(HeapObject::Array(elements), Kind::Array);
+        self.native_roots.push(value);
+        value
+    }
+
+    /// Text of a value as used by `Concat` and the string comparisons. Numbers are
+    /// converted the way `Debug` prints them.
+    fn text(&self, value: Value) -> Result<String, RuntimeError> {
+        let mut text = String::new();
+        self.write_text(value, &mut text, 0)?;
+        Ok(text)
+    }
+
+    fn write_text(
+        &self,
+        value: Value,
+        text: &mut String,
+        depth: usize,
+    ) -> Result<(), RuntimeError> {
+        match value.kind() {
+            Kind::Function => {
+                *text += &format!("<fn {}>", self.callables[value.index()].name);
+            }
+            Kind::Cell if depth >= MAX_PRINT_DEPTH => *text += "<cell ...>",
+            Kind::Null => *text += "null",
+            Kind::Number => *text += &self.expect_number(value)?.to_string(),
+            _ => match self.object(value)? {
+                HeapObject::Text(string) => *text += string,
+                // Structs can contain themselves, so nesting is cut off at some point
+                HeapObject::Struct(_) if depth >= MAX_PRINT_DEPTH => *text += "{...}",
+                HeapObject::Array(_) if depth >= MAX_PRINT_DEPTH => *text += "[...]",
+                HeapObject::Enum(tag, _) if depth >= MAX_PRINT_DEPTH => {
+                    *text += &format!("#{}(...)", tag)
+                }
+                HeapObject::Struct(fields) => {
+                    *text += "{";
+                    for (index, field) in fields.iter().enumerate() {
+                        if index > 0 {
+                            *text += ", ";
+                        }
+                        self.write_text(*field, text, depth + 1)?;
+                    }
+                    *text += "}";
+                }
+                HeapObject::Array(elements) => {
+                    *text += "[";
+                    for (index, element) in elements.iter().enumerate() {
+                        if index > 0 {
+                            *text += ", ";
+                        }
+                        self.write_text(*element, text, depth + 1)?;
+                    }
+                    *text += "]";
+                }
+                HeapObject::Closure(callable, _) => {
+                    *text += &format!("<closure {}>", self.callables[*callable].name)
+                }
+                HeapObject::Regex(regex) => *text += &format!("<regex {}>", regex.as_str()),
+                HeapObject::Cell(value) => {
+                    *text += "<cell ";
+                    self.write_text(*value, text, depth + 1)?;
+                    *text += ">";
+                }
+                HeapObject::Enum(tag, fields) => {
+                    *text += &format!("#{}", tag);
+                    if !fields.is_empty() {
+                        *text += "(";
+                        for (index, field) in fields.iter().enumerate() {
+                            if index > 0 {
+                                *text += ", ";
+                            }
+                            self.write_text(*field, text, depth + 1)?;
+                        }
+                        *text += ")";
+                    }
+                }
+                HeapObject::Free => unreachable!("Live handles never point to freed slots"),
+            },
+        }
+        Ok(())
+    }
+
+    fn object(&self, value: Value) -> Result<&HeapObject, RuntimeError> {
+        if !self.heap.is_live(value.handle()) {
+            return Err(self.error(ErrorKind::DanglingReference(value.kind())));
+        }
+        Ok(&self.heap[value.handle()])
+    }
+
+    fn object_mut(&mut self, value: Value) -> Result<&mut HeapObject, RuntimeError> {
+        if !self.heap.is_live(value.handle()) {
+            return Err(self.error(ErrorKind::DanglingReference(value.kind())));
+        }
+        Ok(&mut self.heap[value.handle()])
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn out_of_bounds(&self, of: Kind, offset: Offset, members: usize) -> RuntimeError {
+        self.error(ErrorKind::MemberOutOfBounds {
+            of,
+            offset,
+            members,
+        })
+    }
+
+    /// The field at `offset` of the struct in `object`.
+    #[inline(never)]
+    fn member(&mut self, object: Register, offset: Offset) -> Result<&mut Value, RuntimeError> {
+        let value = self.get(object);
+        self.expect(value, Kind::Struct)?;
+        let size = match self.object(value)? {
+            HeapObject::Struct(fields) => fields.len(),
+            _ => unreachable!("Structs are boxed as Kind::Struct"),
+        };
+        if offset as usize >= size {
+            return Err(self.out_of_bounds(Kind::Struct, offset, size));
+        }
+        match self.object_mut(value)? {
+            HeapObject::Struct(fields) => Ok(&mut fields[offset as usize]),
+            _ => unreachable!("Structs are boxed as Kind::Struct"),
+        }
+    }
+
+    /// The variant tag and payload of the enum entry in `entry`.
+    #[inline(never)]
+    fn enum_entry(&mut self, entry: Register) -> Result<(Offset, &mut [Value]), RuntimeError> {
+        let value = self.get(entry);
+        self.expect(value, Kind::Enum)?;
+        match self.object_mut(value)? {
+            HeapObject::Enum(tag, fields) => Ok((*tag, fields)),
+            _ => unreachable!("Enum entries are boxed as Kind::Enum"),
+        }
+    }
+
+    /// The payload field at `offset` of the enum entry in `entry`.
+    #[inline(never)]
+    fn enum_member(&mut self, entry: Register, offset: Offset) -> Result<&mut Value, RuntimeError> {
+        let size = self.enum_entry(entry)?.1.len();
+        if offset as usize >= size {
+            return Err(self.out_of_bounds(Kind::Enum, offset, size));
+        }
+        Ok(&mut self.enum_entry(entry)?.1[offset as usize])
+    }
+
+    /// The captured value at `offset` of the running closure.
+    #[inline(never)]
+    fn capture(&mut self, offset: Offset) -> Result<&mut Value, RuntimeError> {
+        if self.closure == NO_CLOSURE {
+            return Err(self.error(ErrorKind::CaptureOutsideClosure));
+        }
+        let closure = Value::boxed(Kind::Closure, self.closure);
+        let size = match self.object(closure)? {
+            HeapObject::Closure(_, captures) => captures.len(),
+            _ => unreachable!("Only closures are invoked with captures"),
+        };
+        if offset as usize >= size {
+            return Err(self.out_of_bounds(Kind::Closure, offset, size));
+        }
+        match self.object_mut(closure)? {
+            HeapObject::Closure(_, captures) => Ok(&mut captures[offset as usize]),
+            _ => unreachable!("Only closures are invoked with captures"),
+        }
+    }
+
+    /// The value held by the cell in `cell`.
+    #[inline(never)]
+    fn cell(&mut self, cell: Register) -> Result<&mut Value, RuntimeError> {
+        let value = self.get(cell);
+        self.expect(value, Kind::Cell)?;
+        match self.object_mut(value)? {
+            HeapObject::Cell(value) => Ok(value),
+            _ => unreachable!("Cells are boxed as Kind::Cell"),
+        }
+    }
+
+    /// The elements of the array in `array`.
+    #[inline(never)]
+    fn array(&mut self, array: Register) -> Result<&mut Vec<Value>, RuntimeError> {
+        let value = self.get(array);
+        self.expect(value, Kind::Array)?;
+        match self.object_mut(value)? {
+            HeapObject::Array(elements) => Ok(elements),
+            _ => unreachable!("Arrays are boxed as Kind::Array"),
+        }
+    }
+
+    /// The element of the array in `array` at the position held by `index`.
+    #[inline(never)]
+    fn element(&mut self, array: Register, index: Register) -> Result<&mut Value, RuntimeError> {
+        let index = self.number(index)?;
+        let length = self.array(array)?.len();
+        if !(index >= 0.0 && index.fract() == 0.0 && (index as usize) < length) {
+            return Err(self.error(ErrorKind::IndexOutOfBounds { index, length }));
+        }
+        Ok(&mut self.array(array)?[index as usize])
+    }
+
+    /// Unwinds `call_stack` until a handler covers the current instruction and continues
+    /// there with `value` in the handler's register. If nothing catches the value, the
+    /// program stops with [ErrorKind::UncaughtThrow].
+    #[inline(never)]
+    fn throw(&mut self, value: Value) -> Result<(), Halt> {
+        let mut trace = vec![];
+        loop {
+            let handler = self
+                .handlers
+                .iter()
+                .filter(|handler| (handler.start..handler.end).contains(&(self.pc as u32)))
+                .min_by_key(|handler| handler.end - handler.start)
+                .copied();
+            if let Some(handler) = handler {
+                self.set(handler.register, value);
+                return self.jump(handler.target as usize);
+            }
+            trace.push(self.source_map.describe(self.pc));
+            if self.stack_pointer == 0 {
+                break;
             }
+            self.stack_pointer -= FRAME_SIZE;
+            let frame = &self.call_stack[self.stack_pointer..self.stack_pointer + FRAME_SIZE];
+            // Continue the search at the InvokeFunction that made the call
+            self.pc = frame[0] - 1;
+            self.activation_record_pointer = frame[1];
+            self.active_record_size = frame[2];
+            self.closure = frame[4];
         }
+
+        Err(Halt::Error(RuntimeError {
+            kind: ErrorKind::UncaughtThrow(self.text(value)?),
+            trace,
+        }))
+    }
+
+    #[inline(always)]
+    fn get(&self, register: Register) -> Value {
+        self.stack[self.activation_record_pointer + register as usize]
+    }
+
+    #[inline(always)]
+    fn set(&mut self, register: Register, value: Value) {
+        self.stack[self.activation_record_pointer + register as usize] = value;
     }
 
-    pub fn running(&self) -> bool {
-        self.running
+    #[inline(always)]
+    fn number(&self, register: Register) -> Result<f64, RuntimeError> {
+        self.expect_number(self.get(register))
     }
+
+    #[inline(always)]
+    fn expect_number(&self, value: Value) -> Result<f64, RuntimeError> {
+        match value.as_number() {
+            Some(number) => Ok(number),
+            None => Err(self.type_error(Kind::Number, value)),
+        }
+    }
+
+    /// Fails with a type error unless `value` is of `kind`.
+    #[inline(always)]
+    fn expect(&self, value: Value, kind: Kind) -> Result<(), RuntimeError> {
+        if value.kind() != kind {
+            return Err(self.type_error(kind, value));
+        }
+        Ok(())
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn type_error(&self, expected: Kind, value: Value) -> RuntimeError {
+        self.error(ErrorKind::TypeMismatch {
+            expected,
+            found: value.kind(),
+        })
+    }
+
+    #[inline(always)]
+    fn set_number(&mut self, register: Register, value: f64) {
+        self.set(register, Value::number(value));
+    }
+
+    /// Comparisons produce `1` for true and `0` for false. Following IEEE 754, every
+    /// comparison involving NaN is false, except `NonEquals`, which is true.
+    #[inline(always)]
+    fn set_bool(&mut self, register: Register, value: bool) {
+        self.set_number(register, if value { 1.0 } else { 0.0 });
+    }
+
+    /// Truthiness used by every conditional instruction: a number is true unless it
+    /// is `0`, `-0` or NaN, null is false and every other value is true. Logical
+    /// instructions produce `1` or `0`.
+    #[inline(always)]
+    fn truthy(&self, register: Register) -> bool {
+        let value = self.get(register);
+        match value.as_number() {
+            Some(number) => number != 0.0 && !number.is_nan(),
+            None => value != Value::NULL,
+        }
+    }
+
+    /// Numbers are equal by value, following IEEE 754; every other value is only equal
+    /// to itself, so two structs with the same fields are different values.
+    #[inline(always)]
+    fn equals(&self, src_a: Register, src_b: Register) -> bool {
+        let (a, b) = (self.get(src_a), self.get(src_b));
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => a == b,
+        }
+    }
+
     pub fn exit_code(&self) -> f64 {
         self.exit_code
     }
 }
 
+/// Why `tick` stopped executing before the next instruction.
+enum Halt {
+    Error(RuntimeError),
+    Interrupted,
+}
+
+impl From<RuntimeError> for Halt {
+    fn from(error: RuntimeError) -> Halt {
+        Halt::Error(error)
+    }
+}
+
+/// Cloneable handle that interrupts a running [VM], usually from another thread. The VM
+/// checks it at backward jumps, including jumps to a `try` handler, and at calls, so a
+/// program stops within one iteration of any loop or recursion.
+#[derive(Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Makes the current or next [VM::run] return [RunOutcome::Interrupted].
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Why [VM::run] returned.
+pub enum RunOutcome {
+    /// The program ended with this exit code
+    Exited(f64),
+    /// The step budget was used up before the program ended
+    OutOfBudget,
+    /// An [InterruptHandle] stopped the program; it can be resumed with another run
+    Interrupted,
+    /// The program stopped with a runtime error; the exit code is 1
+    Failed(RuntimeError),
+}
+
 pub struct Callable {
-    pub name: Box<String>,
+    pub name: String,
     pub registers: i32,
     pub adress: i32,
     pub args: i32,
+    /// Number of values a closure over this function captures
     pub capture_size: i32,
-    pub capture: Box<[Callable]>,
+    /// Index into [Natives] for `extern fn` declarations
+    pub native: Option<usize>,
 }
@@ -0,0 +1,154 @@
+//! Runs every program in `res/tests` and compares what it prints with the
+//! `.expected` file next to it. That file holds the output after the VM starts, without
+//! the timing line, followed by `exit N` with the process exit code.
+//!
+//! Programs in `res/tests/invalid` must be rejected before they run; their `.expected`
+//! file holds the error message. Programs in `res/tests/endless` never end on their own.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn run(program: &Path, flags: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_backend"))
+        .arg(program)
+        .args(flags)
+        .output()
+        .expect("Cant run backend");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let start = stdout
+        .rfind("Starting vm....\n")
+        .map_or(0, |index| index + "Starting vm....\n".len());
+    let mut result: String = stdout[start..]
+        .lines()
+        .filter(|line| !line.starts_with("Took "))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    result += &format!("exit {}\n", output.status.code().unwrap_or(-1));
+    result
+}
+
+fn programs_in(directory: &str) -> Vec<std::path::PathBuf> {
+    let mut programs: Vec<_> = fs::read_dir(directory)
+        .unwrap_or_else(|_| panic!("Cant read {}", directory))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn programs() -> Vec<std::path::PathBuf> {
+    programs_in("res/tests")
+}
+
+#[test]
+fn programs_print_their_expected_output() {
+    let mut failed = vec![];
+    for program in programs() {
+        let expected = fs::read_to_string(program.with_extension("expected"))
+            .unwrap_or_else(|_| panic!("{} has no .expected file", program.display()));
+        let output = run(&program, &[]);
+        if output != expected {
+            failed.push(format!(
+                "{}\n--- expected\n{}--- found\n{}",
+                program.display(),
+                expected,
+                output
+            ));
+        }
+    }
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
+
+/// Collecting before every allocation must not change what a program does.
+#[test]
+fn programs_behave_the_same_under_gc_stress() {
+    for program in programs() {
+        assert_eq!(
+            run(&program, &["--gc-stress"]),
+            run(&program, &[]),
+            "{} with --gc-stress",
+            program.display()
+        );
+    }
+}
+
+#[test]
+fn small_register_limits_overflow() {
+    let output = run(
+        Path::new("res/tests/calls.txt"),
+        &["--stack-limits=1000,10"],
+    );
+    assert!(
+        output.starts_with(
+            "Runtime error: Stack overflow, the limit is 1000 calls or 10 registers\n"
+        ),
+        "{}",
+        output
+    );
+    assert!(output.ends_with("exit 1\n"), "{}", output);
+
+    let output = run(Path::new("res/tests/calls.txt"), &["--stack-limits=1000,1"]);
+    assert!(
+        output.starts_with("Runtime error: Stack overflow"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn invalid_programs_are_rejected() {
+    for program in programs_in("res/tests/invalid") {
+        let expected = fs::read_to_string(program.with_extension("expected"))
+            .unwrap_or_else(|_| panic!("{} has no .expected file", program.display()));
+        let output = Command::new(env!("CARGO_BIN_EXE_backend"))
+            .arg(&program)
+            .output()
+            .expect("Cant run backend");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{} ran", program.display());
+        assert!(
+            stderr.contains(expected.trim()),
+            "{}\n--- expected\n{}--- found\n{}",
+            program.display(),
+            expected,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn budget_stops_the_program() {
+    let output = run(Path::new("res/tests/calls.txt"), &["--budget=10"]);
+    assert_eq!(output, "Stopped after 10 steps\nexit 0\n");
+}
+
+/// Running in short slices resumes each time where the previous slice stopped.
+#[test]
+fn programs_resume_after_running_out_of_budget() {
+    for program in programs() {
+        assert_eq!(
+            run(&program, &["--slice=3"]),
+            run(&program, &[]),
+            "{} with --slice=3",
+            program.display()
+        );
+    }
+}
+
+/// Endless programs stop at their loop label once `--timeout` interrupts them. The
+/// budget only makes a missed interrupt fail the test instead of hanging it.
+#[test]
+fn timeout_interrupts_endless_programs() {
+    for program in programs_in("res/tests/endless") {
+        let output = run(&program, &["--timeout=50", "--budget=500000000"]);
+        let expected = format!("Interrupted at {}:", program.display());
+        assert!(
+            output.starts_with(&expected) && output.contains(" in Main.main at #loop\n"),
+            "{}: {}",
+            program.display(),
+            output
+        );
+    }
+}